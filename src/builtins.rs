@@ -0,0 +1,389 @@
+use crate::{calibration, dry_run::{self, SimulatedActionKind}, handler, history::SensorHistory, library::{self, Arguments}, lifecycle, metrics::VALVE_STATES, operator::{self, FlightReport}, state::SharedState, units::UnitOfMeasure, SLEEP_POLL_PERIOD};
+use common::comm::VehicleState;
+use pyo3::{create_exception, exceptions::{PyException, PyRuntimeError, PyValueError}, pyfunction, types::PyDict, wrap_pyfunction, PyResult, Python};
+use std::{sync::OnceLock, thread, time::{Duration, Instant}};
+
+create_exception!(flight, ValveConflictError, PyException, "Raised when a sequence actuates a valve claimed by another running sequence.");
+create_exception!(flight, StaleReadingError, PyException, "Raised when a sequence reads a sensor whose latest reading is stale.");
+
+/// The shared state used by builtins, which are called from Python without any context.
+static SHARED: OnceLock<SharedState> = OnceLock::new();
+
+/// Registers flight-side functions as Python builtins, making them available to every
+/// sequence and trigger alongside those provided by `common::sequence`.
+pub fn initialize(shared: SharedState) -> PyResult<()> {
+	// only the first initialization is kept, which is fine because shared state is never replaced
+	let _ = SHARED.set(shared);
+
+	Python::with_gil(|py| {
+		let builtins = py.import("builtins")?;
+		builtins.add_function(wrap_pyfunction!(read_sensor_as, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(sensor_min, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(sensor_max, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(sensor_mean, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(sensor_stddev, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(sensor_rate, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(sensor_value_at, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(calibrate, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(release_valve, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(wait_until_sensor, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(wait_until_valve, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(start_sequence, builtins)?)?;
+		builtins.add_function(wrap_pyfunction!(call_sequence, builtins)?)?;
+		builtins.add("ValveConflictError", py.get_type::<ValveConflictError>())?;
+		builtins.add("StaleReadingError", py.get_type::<StaleReadingError>())?;
+
+		// replace time.sleep so that sleeping sequences can be stopped promptly
+		let time = py.import("time")?;
+		time.add_function(wrap_pyfunction!(sleep, time)?)?;
+
+		Ok(())
+	})
+}
+
+fn shared() -> &'static SharedState {
+	SHARED.get().expect("builtins were called before being initialized")
+}
+
+/// Converts a number of seconds passed from Python into a `Duration`, raising
+/// `ValueError` if it is negative or not finite.
+fn seconds(seconds: f64) -> PyResult<Duration> {
+	Duration::try_from_secs_f64(seconds)
+		.map_err(|error| PyValueError::new_err(error.to_string()))
+}
+
+/// Runs a query against the history of a sensor as of now, returning `None` if it has no history.
+fn query_history<T>(name: &str, query: impl FnOnce(&SensorHistory, Instant) -> Option<T>) -> PyResult<Option<T>> {
+	let shared = shared();
+
+	// triggers aren't registered as running sequences, so only check sequences
+	if lifecycle::in_sequence() {
+		handler::check_running(shared)?;
+	}
+
+	let now = shared.clock.now();
+	let sensor_history = shared.sensor_history.lock().unwrap();
+	Ok(sensor_history.get(name).and_then(|history| query(history, now)))
+}
+
+/// Reads the latest value of a sensor converted to the given unit, returning `None` if it
+/// has never been read, raising `StaleReadingError` if it is stale and raising `ValueError`
+/// if the units are incompatible.
+#[pyfunction]
+fn read_sensor_as(name: &str, unit: &str) -> PyResult<Option<f64>> {
+	let shared = shared();
+
+	// triggers aren't registered as running sequences, so only check sequences
+	if lifecycle::in_sequence() {
+		handler::check_running(shared)?;
+	}
+
+	let target = unit
+		.parse::<UnitOfMeasure>()
+		.map_err(PyValueError::new_err)?;
+
+	let Some(measurement) = handler::fresh_measurement(name, &shared.vehicle_state, &shared.reading_timestamps, shared.clock.now())? else {
+		return Ok(None);
+	};
+
+	UnitOfMeasure::from(measurement.unit)
+		.convert(measurement.value, target)
+		.map(Some)
+		.map_err(|error| PyValueError::new_err(error.to_string()))
+}
+
+/// Minimum value of a sensor over the last `window` seconds.
+#[pyfunction]
+fn sensor_min(name: &str, window: f64) -> PyResult<Option<f64>> {
+	let window = seconds(window)?;
+	query_history(name, |history, now| history.statistics(window, now).map(|statistics| statistics.min))
+}
+
+/// Maximum value of a sensor over the last `window` seconds.
+#[pyfunction]
+fn sensor_max(name: &str, window: f64) -> PyResult<Option<f64>> {
+	let window = seconds(window)?;
+	query_history(name, |history, now| history.statistics(window, now).map(|statistics| statistics.max))
+}
+
+/// Mean value of a sensor over the last `window` seconds.
+#[pyfunction]
+fn sensor_mean(name: &str, window: f64) -> PyResult<Option<f64>> {
+	let window = seconds(window)?;
+	query_history(name, |history, now| history.statistics(window, now).map(|statistics| statistics.mean))
+}
+
+/// Standard deviation of a sensor over the last `window` seconds.
+#[pyfunction]
+fn sensor_stddev(name: &str, window: f64) -> PyResult<Option<f64>> {
+	let window = seconds(window)?;
+	query_history(name, |history, now| history.statistics(window, now).map(|statistics| statistics.stddev))
+}
+
+/// Rate of change of a sensor per second over the last `window` seconds.
+#[pyfunction]
+fn sensor_rate(name: &str, window: f64) -> PyResult<Option<f64>> {
+	let window = seconds(window)?;
+	query_history(name, |history, now| history.rate_of_change(window, now))
+}
+
+/// Value of a sensor as it was `age` seconds ago.
+#[pyfunction]
+fn sensor_value_at(name: &str, age: f64) -> PyResult<Option<f64>> {
+	let age = seconds(age)?;
+	query_history(name, |history, now| history.value_at(age, now))
+}
+
+/// Zeroes a sensor by averaging its readings over the next `window` seconds while it is
+/// at `expected`, returning the new calibrated offset and reporting it to the server.
+#[pyfunction]
+#[pyo3(signature = (name, window, expected = 0.0))]
+fn calibrate(py: Python<'_>, name: &str, window: f64, expected: f64) -> PyResult<f64> {
+	let shared = shared();
+
+	// triggers aren't registered as running sequences, so only check sequences
+	if lifecycle::in_sequence() {
+		handler::check_running(shared)?;
+	}
+
+	let window = seconds(window)?;
+	let kind = SimulatedActionKind::Calibrate { name: name.to_owned(), window, expected };
+
+	if dry_run::skip(kind, window)? {
+		// nothing is applied in a dry run, so the offset is left as it was
+		return Ok(shared.mappings
+			.lock()
+			.unwrap()
+			.iter()
+			.find(|mapping| mapping.text_id == name)
+			.map_or(0.0, |mapping| mapping.calibrated_offset));
+	}
+
+	// release the GIL so other sequences and triggers run during the calibration window
+	let result = py.allow_threads(|| calibration::calibrate(shared, name, window, expected));
+
+	match result {
+		Ok(calibration) => {
+			let offset = calibration.offset;
+			operator::report(shared, &FlightReport::Calibrated(calibration));
+			Ok(offset)
+		},
+		Err(reason) => {
+			operator::report(shared, &FlightReport::CalibrationFailed { text_id: name.to_owned(), reason: reason.clone() });
+			Err(PyRuntimeError::new_err(reason))
+		},
+	}
+}
+
+/// Sleeps for the given number of seconds in place of `time.sleep`, raising `AbortError`
+/// promptly if the calling sequence is stopped or aborted in the meantime.
+#[pyfunction]
+fn sleep(py: Python<'_>, secs: f64) -> PyResult<()> {
+	let duration = seconds(secs)?;
+
+	if dry_run::sleep(duration)? {
+		return Ok(());
+	}
+
+	let deadline = Instant::now() + duration;
+
+	loop {
+		// triggers aren't registered as running sequences, so only check sequences
+		if lifecycle::in_sequence() {
+			handler::check_running(shared())?;
+		}
+
+		py.check_signals()?;
+
+		let now = Instant::now();
+
+		if now >= deadline {
+			return Ok(());
+		}
+
+		py.allow_threads(|| thread::sleep((deadline - now).min(SLEEP_POLL_PERIOD)));
+	}
+}
+
+/// Releases the calling sequence's claim on a valve so that other sequences may actuate it.
+#[pyfunction]
+fn release_valve(name: &str) -> PyResult<()> {
+	let shared = shared();
+
+	// triggers aren't registered as running sequences, so only check sequences
+	if lifecycle::in_sequence() {
+		handler::check_running(shared)?;
+	}
+
+	if dry_run::skip(SimulatedActionKind::ReleaseValve(name.to_owned()), Duration::ZERO)? {
+		return Ok(());
+	}
+
+	handler::release_valve(shared, name);
+	Ok(())
+}
+
+/// Blocks until `condition`, described by `description`, holds for the vehicle state or
+/// `timeout` elapses, returning whether it was met.
+///
+/// The condition is re-evaluated whenever the worker updates the vehicle state, so it is
+/// met as soon as the data arrives rather than at the next poll.
+fn wait_until(py: Python<'_>, description: &str, timeout: f64, condition: impl Fn(&VehicleState) -> bool + Send + Sync) -> PyResult<bool> {
+	let shared = shared();
+
+	// triggers aren't registered as running sequences, so only check sequences
+	let in_sequence = lifecycle::in_sequence();
+
+	if in_sequence {
+		handler::check_running(shared)?;
+	}
+
+	let timeout = seconds(timeout)?;
+
+	if let Some(met) = dry_run::wait_until(description, timeout, &condition)? {
+		return Ok(met);
+	}
+
+	let deadline = Instant::now() + timeout;
+
+	// release the GIL so other sequences and triggers run while waiting
+	py.allow_threads(|| {
+		loop {
+			let vehicle_state = shared.vehicle_state.lock().unwrap();
+
+			if condition(&vehicle_state) {
+				return Ok(true);
+			}
+
+			let now = Instant::now();
+
+			if now >= deadline {
+				return Ok(false);
+			}
+
+			// wake periodically even without new data so that a stopped sequence exits promptly
+			let timeout = (deadline - now).min(SLEEP_POLL_PERIOD);
+			drop(shared.vehicle_updated.wait_timeout(vehicle_state, timeout).unwrap());
+
+			if in_sequence {
+				handler::check_running(shared)?;
+			}
+		}
+	})
+}
+
+/// Waits until a fresh reading of a sensor compares to `threshold` as given by `comparison`,
+/// one of `<`, `<=`, `>` or `>=`, returning `False` if `timeout` seconds elapse first.
+#[pyfunction]
+fn wait_until_sensor(py: Python<'_>, name: &str, comparison: &str, threshold: f64, timeout: f64) -> PyResult<bool> {
+	let compare: fn(f64, f64) -> bool = match comparison {
+		"<" => |value, threshold| value < threshold,
+		"<=" => |value, threshold| value <= threshold,
+		">" => |value, threshold| value > threshold,
+		">=" => |value, threshold| value >= threshold,
+		_ => return Err(PyValueError::new_err(format!("unknown comparison '{comparison}'"))),
+	};
+
+	let shared = shared();
+
+	let description = format!("{name} {comparison} {threshold}");
+
+	wait_until(py, &description, timeout, |vehicle_state| {
+		let stale = shared.reading_timestamps
+			.lock()
+			.unwrap()
+			.get(name)
+			.is_some_and(|timestamp| timestamp.is_stale(shared.clock.now()));
+
+		!stale && vehicle_state.sensor_readings
+			.get(name)
+			.is_some_and(|measurement| compare(measurement.value, threshold))
+	})
+}
+
+/// Waits until the actual state of a valve is `state`, as returned by `read_valve_state`,
+/// returning `False` if `timeout` seconds elapse first.
+#[pyfunction]
+fn wait_until_valve(py: Python<'_>, name: &str, state: &str, timeout: f64) -> PyResult<bool> {
+	let Some(state) = VALVE_STATES.into_iter().find(|candidate| candidate.to_string() == state) else {
+		return Err(PyValueError::new_err(format!("unknown valve state '{state}'")));
+	};
+
+	let description = format!("{name} is {state}");
+
+	wait_until(py, &description, timeout, |vehicle_state| {
+		vehicle_state.valve_states
+			.get(name)
+			.is_some_and(|valve| valve.actual == state)
+	})
+}
+
+/// Starts a sequence from the onboard library on its own thread, passing any keyword
+/// arguments to it as `args`. Raises `RuntimeError` if it can't be started.
+#[pyfunction]
+#[pyo3(signature = (name, replace = false, **arguments))]
+fn start_sequence(name: &str, replace: bool, arguments: Option<&PyDict>) -> PyResult<()> {
+	let shared = shared();
+
+	// triggers aren't registered as running sequences, so only check sequences
+	if lifecycle::in_sequence() {
+		handler::check_running(shared)?;
+	}
+
+	let arguments: Arguments = arguments.map_or(Ok(Arguments::new()), |arguments| arguments.extract())?;
+
+	if dry_run::skip(SimulatedActionKind::StartSequence(name.to_owned()), Duration::ZERO)? {
+		return Ok(());
+	}
+
+	library::start(shared, name, arguments, replace).map_err(PyRuntimeError::new_err)
+}
+
+/// Runs a sequence from the onboard library on the calling thread as if it were part of
+/// the caller, passing any keyword arguments to it as `args`.
+#[pyfunction]
+#[pyo3(signature = (name, **arguments))]
+fn call_sequence(py: Python<'_>, name: &str, arguments: Option<&PyDict>) -> PyResult<()> {
+	let shared = shared();
+
+	if lifecycle::in_sequence() {
+		handler::check_running(shared)?;
+	}
+
+	let Some(sequence) = library::get(shared, name) else {
+		return Err(PyValueError::new_err(format!("sequence '{name}' is not in the library")));
+	};
+
+	let arguments = arguments.unwrap_or_else(|| PyDict::new(py));
+	py.run(&sequence.script, Some(handler::sequence_globals(py, Some(arguments))?), None)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn history_queries_work_in_trigger_conditions() {
+		pyo3::prepare_freethreaded_python();
+
+		// builtins keep the first shared state they're given, so use whichever one that was
+		let _ = initialize(SharedState::new());
+		let shared = shared();
+		let now = shared.clock.now();
+		let mut history = SensorHistory::new();
+
+		for seconds in 0..=4 {
+			history.push(now - Duration::from_secs(4 - seconds), seconds as f64 * 2.0);
+		}
+
+		shared.sensor_history.lock().unwrap().insert("trigger_pressure".to_owned(), history);
+
+		// conditions are evaluated the way the trigger thread does, outside of any sequence
+		let met = Python::with_gil(|py| {
+			py.eval("sensor_rate('trigger_pressure', 10.0) > 1.5 and sensor_max('trigger_pressure', 10.0) == 8.0", None, None)
+				.and_then(|condition| condition.extract::<bool>())
+		});
+
+		assert!(met.unwrap());
+	}
+}
@@ -0,0 +1,189 @@
+use crate::{operator::{self, FlightReport}, persistence, state::SharedState, CALIBRATION_FILE};
+use common::comm::{NodeMapping, SensorType};
+use jeflog::{fail, pass, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, thread, time::Duration};
+
+/// The outcome of a successful zero-offset calibration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Calibration {
+	/// The text ID of the calibrated mapping.
+	pub text_id: String,
+
+	/// The calibrated offset of the mapping before calibration.
+	pub previous_offset: f64,
+
+	/// The newly computed and applied calibrated offset.
+	pub offset: f64,
+
+	/// The mean reading over the calibration window, before the new offset was applied.
+	pub mean: f64,
+}
+
+/// An offset calibrated onboard, persisted so that it overrides the offset sent by the server
+/// across restarts.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+struct PersistedOffset {
+	/// The offset calibrated onboard.
+	offset: f64,
+
+	/// The offset sent by the server before the sensor was first calibrated onboard. If the
+	/// server sends a different offset, it is newer and takes precedence.
+	server_offset: f64,
+}
+
+/// Zeroes a sensor by averaging its readings over `window` while it is known to be at
+/// `expected`, then applying and persisting the corrected offset.
+///
+/// This blocks for the duration of the window.
+pub fn calibrate(shared: &SharedState, text_id: &str, window: Duration, expected: f64) -> Result<Calibration, String> {
+	let sensor_type = shared.mappings
+		.lock()
+		.unwrap()
+		.iter()
+		.find(|mapping| mapping.text_id == text_id)
+		.map(|mapping| mapping.sensor_type)
+		.ok_or_else(|| format!("mapping '{text_id}' is not defined"))?;
+
+	// only these sensor types have their calibrated offset applied by the worker
+	if !matches!(sensor_type, SensorType::Pt | SensorType::LoadCell) {
+		return Err(format!("mapping '{text_id}' is a {sensor_type:?}, which cannot be calibrated"));
+	}
+
+	thread::sleep(window);
+
+	let stale = shared.reading_timestamps
+		.lock()
+		.unwrap()
+		.get(text_id)
+		.map_or(true, |timestamp| timestamp.is_stale(shared.clock.now()));
+
+	if stale {
+		return Err(format!("sensor '{text_id}' has no fresh readings"));
+	}
+
+	let mean = shared.sensor_history
+		.lock()
+		.unwrap()
+		.get(text_id)
+		.and_then(|history| history.statistics(window, shared.clock.now()))
+		.map(|statistics| statistics.mean)
+		.ok_or_else(|| format!("sensor '{text_id}' had no readings during the calibration window"))?;
+
+	let mut mappings = shared.mappings.lock().unwrap();
+
+	let mapping = mappings
+		.iter_mut()
+		.find(|mapping| mapping.text_id == text_id)
+		.ok_or_else(|| format!("mapping '{text_id}' was removed during calibration"))?;
+
+	// the offset is subtracted from the reading, so any error in the mean is added to it
+	let previous_offset = mapping.calibrated_offset;
+	let offset = previous_offset + mean - expected;
+	mapping.calibrated_offset = offset;
+	drop(mappings);
+
+	let mut offsets = load();
+
+	// only the first onboard calibration replaced the server's offset
+	let server_offset = offsets
+		.get(text_id)
+		.map_or(previous_offset, |persisted| persisted.server_offset);
+
+	offsets.insert(text_id.to_owned(), PersistedOffset { offset, server_offset });
+
+	if let Err(error) = persist(&offsets) {
+		warn!("Applied calibration of '{text_id}' but failed to persist it: {error}");
+	}
+
+	Ok(Calibration { text_id: text_id.to_owned(), previous_offset, offset, mean })
+}
+
+/// Calibrates a sensor on a separate thread and reports the outcome to the server.
+pub fn calibrate_in_background(shared: SharedState, text_id: String, window: Duration, expected: f64) {
+	thread::spawn(move || {
+		let report = match calibrate(&shared, &text_id, window, expected) {
+			Ok(calibration) => {
+				pass!("Calibrated '{text_id}' with offset {} (previously {}).", calibration.offset, calibration.previous_offset);
+				FlightReport::Calibrated(calibration)
+			},
+			Err(reason) => {
+				fail!("Failed to calibrate '{text_id}': {reason}.");
+				FlightReport::CalibrationFailed { text_id, reason }
+			},
+		};
+
+		operator::report(&shared, &report);
+	});
+}
+
+/// Overrides the calibrated offsets of the given mappings with those calibrated onboard,
+/// unless the server has sent a newer offset since, in which case the persisted offset is
+/// discarded.
+pub fn apply_persisted(mappings: &mut [NodeMapping]) {
+	let mut offsets = load();
+	let count = offsets.len();
+
+	for mapping in mappings {
+		let Some(persisted) = offsets.get(&mapping.text_id) else {
+			continue;
+		};
+
+		if mapping.calibrated_offset != persisted.server_offset {
+			pass!("Server sent a new calibrated offset of {} for '{}', discarding the offset calibrated onboard.", mapping.calibrated_offset, mapping.text_id);
+			offsets.remove(&mapping.text_id);
+			continue;
+		}
+
+		pass!("Applying persisted calibrated offset of {} to '{}'.", persisted.offset, mapping.text_id);
+		mapping.calibrated_offset = persisted.offset;
+	}
+
+	if offsets.len() != count {
+		if let Err(error) = persist(&offsets) {
+			warn!("Failed to persist discarded calibrations: {error}");
+		}
+	}
+}
+
+/// Discards the offset calibrated onboard for the given mapping, or for every mapping if
+/// none is given, restoring the offset sent by the server. Returns the text ID of every
+/// mapping whose offset was discarded.
+pub fn clear(shared: &SharedState, text_id: Option<&str>) -> Vec<String> {
+	let mut offsets = load();
+	let mut mappings = shared.mappings.lock().unwrap();
+	let mut cleared = Vec::new();
+
+	offsets.retain(|id, persisted| {
+		if text_id.is_some_and(|text_id| text_id != id) {
+			return true;
+		}
+
+		if let Some(mapping) = mappings.iter_mut().find(|mapping| mapping.text_id == *id) {
+			mapping.calibrated_offset = persisted.server_offset;
+		}
+
+		cleared.push(id.clone());
+		false
+	});
+
+	drop(mappings);
+
+	if !cleared.is_empty() {
+		if let Err(error) = persist(&offsets) {
+			warn!("Cleared calibrations but failed to persist them: {error}");
+		}
+	}
+
+	cleared
+}
+
+/// Loads the persisted calibrated offsets, or none if they cannot be read.
+fn load() -> HashMap<String, PersistedOffset> {
+	persistence::load(CALIBRATION_FILE).unwrap_or_default()
+}
+
+/// Persists the given calibrated offsets, replacing any previously persisted.
+fn persist(offsets: &HashMap<String, PersistedOffset>) -> Result<(), String> {
+	persistence::persist(CALIBRATION_FILE, offsets)
+}
@@ -0,0 +1,30 @@
+use std::{sync::{Arc, Mutex}, time::Instant};
+
+/// The time against which data from boards is timestamped and aged.
+///
+/// This follows the wall clock, except during a replay, where it follows the times of the
+/// recorded records so that staleness, persistence and settle times don't depend on the
+/// speed of the replay.
+#[derive(Clone, Debug, Default)]
+pub struct Clock {
+	/// The time of the record being replayed, if replaying.
+	replayed: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Clock {
+	/// The current time.
+	pub fn now(&self) -> Instant {
+		let replayed = *self.replayed.lock().unwrap();
+		replayed.unwrap_or_else(Instant::now)
+	}
+
+	/// Whether the clock follows a replay rather than the wall clock.
+	pub fn is_replaying(&self) -> bool {
+		self.replayed.lock().unwrap().is_some()
+	}
+
+	/// Stops following the wall clock, setting the current time to the given instant.
+	pub fn set(&self, now: Instant) {
+		*self.replayed.lock().unwrap() = Some(now);
+	}
+}
@@ -0,0 +1,487 @@
+use crate::{events::{self, EventKind, Severity}, handler, library::{self, Arguments}, lifecycle, operator::{self, FlightReport}, state::SharedState, validation, CommandSender, COUNTDOWN_TICK_PERIOD};
+use common::comm::{Sequence, SensorType, ValveState};
+use jeflog::{fail, pass, warn};
+use serde::{Deserialize, Serialize};
+use std::{thread, time::{Duration, Instant}};
+
+/// Commands from the operator which control the countdown clock and its schedule.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum CountdownCommand {
+	/// Starts counting with T-0 the given time from now, arming everything scheduled from
+	/// then on. Anything scheduled before then is never executed.
+	Start {
+		t_minus: Duration,
+	},
+
+	/// Stops the clock where it is, so nothing scheduled is executed until it resumes.
+	Hold,
+
+	/// Resumes counting from where the clock was held.
+	Resume,
+
+	/// Returns the clock to the given time before T-0 and holds there, re-arming everything
+	/// scheduled from then on. Anything scheduled before then is never executed.
+	Recycle {
+		t_minus: Duration,
+	},
+
+	/// Stops the countdown entirely, keeping the schedule.
+	Cancel,
+
+	/// Schedules an action, replacing any scheduled with the same name.
+	Schedule(ScheduledItem),
+
+	/// Removes the scheduled action with the given name.
+	Unschedule(String),
+}
+
+/// An action executed by the flight computer at a fixed time relative to T-0.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScheduledItem {
+	pub name: String,
+
+	/// Seconds relative to T-0 at which the action is executed, negative before T-0.
+	pub offset: f64,
+
+	pub action: ScheduledAction,
+}
+
+/// What is done when a scheduled item comes due.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ScheduledAction {
+	/// Runs the given sequence on its own thread.
+	RunSequence(Sequence),
+
+	/// Starts a sequence from the onboard library on its own thread.
+	StartSequence {
+		name: String,
+		arguments: Arguments,
+	},
+
+	/// Actuates a single valve directly, without running a sequence.
+	ActuateValve {
+		name: String,
+		state: ValveState,
+	},
+}
+
+/// The countdown clock as it is included in telemetry.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct CountdownClock {
+	/// Seconds relative to T-0, negative before T-0.
+	pub time: f64,
+
+	/// Whether the clock is held.
+	pub holding: bool,
+}
+
+/// Where the countdown clock is.
+#[derive(Clone, Copy, Debug)]
+enum ClockState {
+	/// No countdown is in progress.
+	Idle,
+
+	/// Counting toward or past T-0, which is at the given instant.
+	Counting {
+		t_zero: Instant,
+	},
+
+	/// Held at the given number of seconds relative to T-0.
+	Holding {
+		time: f64,
+	},
+}
+
+/// The countdown clock and every action scheduled relative to T-0.
+#[derive(Debug)]
+pub struct Countdown {
+	state: ClockState,
+
+	/// Every scheduled item and whether it has already been executed during this countdown.
+	schedule: Vec<(ScheduledItem, bool)>,
+}
+
+impl Default for Countdown {
+	fn default() -> Self {
+		Countdown {
+			state: ClockState::Idle,
+			schedule: Vec::new(),
+		}
+	}
+}
+
+impl Countdown {
+	/// The current time on the clock, or `None` if no countdown is in progress.
+	pub fn clock(&self) -> Option<CountdownClock> {
+		match self.state {
+			ClockState::Idle => None,
+			ClockState::Counting { t_zero } => Some(CountdownClock { time: seconds_since(t_zero), holding: false }),
+			ClockState::Holding { time } => Some(CountdownClock { time, holding: true }),
+		}
+	}
+
+	/// Re-arms every scheduled item at or after the given time, and marks everything before
+	/// it as executed so that it isn't executed retroactively.
+	fn rearm(&mut self, time: f64) {
+		for (item, fired) in &mut self.schedule {
+			*fired = item.offset < time;
+		}
+	}
+
+	/// Starts counting from the given time relative to T-0, failing if it is out of range.
+	fn start(&mut self, time: f64) -> Result<(), String> {
+		let t_zero = t_zero_at(time).ok_or_else(|| out_of_range(time))?;
+		self.state = ClockState::Counting { t_zero };
+		self.rearm(time);
+		Ok(())
+	}
+
+	/// Holds the clock where it is, returning the time it was held at, or `None` if it
+	/// wasn't counting.
+	fn hold(&mut self) -> Option<f64> {
+		let clock = self.clock().filter(|clock| !clock.holding)?;
+		self.state = ClockState::Holding { time: clock.time };
+		Some(clock.time)
+	}
+
+	/// Resumes counting from where the clock was held, returning the time it resumed at.
+	fn resume(&mut self) -> Result<f64, String> {
+		let ClockState::Holding { time } = self.state else {
+			return Err("the countdown is not held, so it can't be resumed".to_owned());
+		};
+
+		let t_zero = t_zero_at(time).ok_or_else(|| out_of_range(time))?;
+		self.state = ClockState::Counting { t_zero };
+		Ok(time)
+	}
+
+	/// Returns the clock to the given time relative to T-0 and holds there, failing if it
+	/// is too far out to ever be resumed from.
+	fn recycle(&mut self, time: f64) -> Result<(), String> {
+		t_zero_at(time).ok_or_else(|| out_of_range(time))?;
+		self.state = ClockState::Holding { time };
+		self.rearm(time);
+		Ok(())
+	}
+
+	/// Stops the countdown entirely, re-arming everything scheduled.
+	fn cancel(&mut self) {
+		self.state = ClockState::Idle;
+		self.rearm(f64::NEG_INFINITY);
+	}
+
+	/// Takes every armed item which is due while counting, marking each as executed.
+	fn due(&mut self) -> Vec<ScheduledItem> {
+		let ClockState::Counting { t_zero } = self.state else {
+			return Vec::new();
+		};
+
+		let time = seconds_since(t_zero);
+		let mut due = Vec::new();
+
+		for (item, fired) in &mut self.schedule {
+			if !*fired && item.offset <= time {
+				*fired = true;
+				due.push(item.clone());
+			}
+		}
+
+		// execute in the order they were scheduled for if several came due at once
+		due.sort_by(|a, b| a.offset.total_cmp(&b.offset));
+		due
+	}
+}
+
+/// Seconds elapsed since the given instant, negative if it is in the future.
+fn seconds_since(instant: Instant) -> f64 {
+	let now = Instant::now();
+
+	if now >= instant {
+		(now - instant).as_secs_f64()
+	} else {
+		-(instant - now).as_secs_f64()
+	}
+}
+
+/// The instant of T-0 if the clock reads the given number of seconds relative to it now,
+/// or `None` if that instant can't be represented.
+fn t_zero_at(time: f64) -> Option<Instant> {
+	let now = Instant::now();
+	let offset = Duration::try_from_secs_f64(time.abs()).ok()?;
+
+	if time < 0.0 {
+		now.checked_add(offset)
+	} else {
+		now.checked_sub(offset)
+	}
+}
+
+/// Describes a time which the clock can't be set to.
+fn out_of_range(time: f64) -> String {
+	format!("{} is out of range", format_time(time))
+}
+
+/// Formats a time relative to T-0 as it is called out, such as `T-10.000`.
+fn format_time(time: f64) -> String {
+	if time < 0.0 {
+		format!("T-{:.3}", -time)
+	} else {
+		format!("T+{time:.3}")
+	}
+}
+
+/// Executes a countdown command from the operator, reporting why if it is refused.
+pub fn control(shared: &SharedState, command: CountdownCommand) {
+	let message = match apply(shared, command) {
+		Ok(message) => message,
+		Err(reason) => {
+			fail!("Refused countdown command: {reason}.");
+			operator::report(shared, &FlightReport::CountdownRejected(reason));
+			return;
+		},
+	};
+
+	pass!("{message}");
+	events::emit(shared, Severity::Info, module_path!(), EventKind::Countdown(message));
+}
+
+/// Applies a countdown command, returning the message announcing it or why it was refused.
+fn apply(shared: &SharedState, command: CountdownCommand) -> Result<String, String> {
+	if let CountdownCommand::Schedule(item) = &command {
+		check_item(shared, item).map_err(|reason| format!("can't schedule '{}' because {reason}", item.name))?;
+	}
+
+	let restarting = matches!(command, CountdownCommand::Start { .. } | CountdownCommand::Resume);
+
+	if restarting && *shared.abort_latched.lock().unwrap() {
+		return Err("the countdown can't be started or resumed until the vehicle is re-armed after its abort".to_owned());
+	}
+
+	let mut countdown = shared.countdown.lock().unwrap();
+
+	let message = match command {
+		CountdownCommand::Start { t_minus } => {
+			let time = -t_minus.as_secs_f64();
+			countdown.start(time)?;
+			format!("Countdown started at {}.", format_time(time))
+		},
+		CountdownCommand::Hold => {
+			let time = countdown.hold().ok_or("the countdown is not counting, so it can't be held")?;
+			format!("Countdown held at {}.", format_time(time))
+		},
+		CountdownCommand::Resume => {
+			let time = countdown.resume()?;
+			format!("Countdown resumed at {}.", format_time(time))
+		},
+		CountdownCommand::Recycle { t_minus } => {
+			let time = -t_minus.as_secs_f64();
+			countdown.recycle(time)?;
+			format!("Countdown recycled to {} and held.", format_time(time))
+		},
+		CountdownCommand::Cancel => {
+			countdown.cancel();
+			"Countdown cancelled.".to_owned()
+		},
+		CountdownCommand::Schedule(item) => {
+			let message = format!("Scheduled '{}' at {}.", item.name, format_time(item.offset));
+
+			// an item scheduled for a time which has already passed is never executed retroactively
+			let fired = countdown.clock().is_some_and(|clock| item.offset < clock.time);
+
+			if fired {
+				warn!("Scheduled '{}' for {}, which has already passed during this countdown.", item.name, format_time(item.offset));
+			}
+
+			countdown.schedule.retain(|(existing, _)| existing.name != item.name);
+			countdown.schedule.push((item, fired));
+			message
+		},
+		CountdownCommand::Unschedule(name) => {
+			let count = countdown.schedule.len();
+			countdown.schedule.retain(|(existing, _)| existing.name != name);
+
+			if countdown.schedule.len() == count {
+				return Err(format!("nothing named '{name}' is scheduled"));
+			}
+
+			format!("Unscheduled '{name}'.")
+		},
+	};
+
+	Ok(message)
+}
+
+/// Holds the countdown if it is counting, so that nothing more is executed after an abort.
+pub fn hold(shared: &SharedState) {
+	let counting = shared.countdown
+		.lock()
+		.unwrap()
+		.clock()
+		.is_some_and(|clock| !clock.holding);
+
+	if counting {
+		control(shared, CountdownCommand::Hold);
+	}
+}
+
+/// Checks that a scheduled item refers to a valid sequence or a mapped valve.
+fn check_item(shared: &SharedState, item: &ScheduledItem) -> Result<(), String> {
+	if !item.offset.is_finite() {
+		return Err("its offset is not finite".to_owned());
+	}
+
+	match &item.action {
+		ScheduledAction::RunSequence(sequence) => validation::check(shared, sequence)
+			.then_some(())
+			.ok_or_else(|| format!("sequence '{}' failed validation", sequence.name)),
+		ScheduledAction::StartSequence { name, .. } => library::get(shared, name)
+			.map(|_| ())
+			.ok_or_else(|| format!("sequence '{name}' is not in the library")),
+		ScheduledAction::ActuateValve { name, .. } => shared.mappings
+			.lock()
+			.unwrap()
+			.iter()
+			.any(|mapping| mapping.text_id == *name && matches!(mapping.sensor_type, SensorType::Valve))
+			.then_some(())
+			.ok_or_else(|| format!("'{name}' is not a mapped valve")),
+	}
+}
+
+/// Executes every scheduled item as it comes due while the countdown is counting.
+pub fn run(shared: SharedState, command_tx: CommandSender) -> impl FnOnce() {
+	move || {
+		loop {
+			let due = shared.countdown.lock().unwrap().due();
+
+			for item in due {
+				execute(&shared, &command_tx, item);
+			}
+
+			thread::sleep(COUNTDOWN_TICK_PERIOD);
+		}
+	}
+}
+
+/// Executes a single scheduled item.
+fn execute(shared: &SharedState, command_tx: &CommandSender, item: ScheduledItem) {
+	pass!("{}: executing '{}'.", format_time(item.offset), item.name);
+
+	let result = match item.action {
+		ScheduledAction::RunSequence(sequence) => lifecycle::spawn(shared, sequence, None, false),
+		ScheduledAction::StartSequence { name, arguments } => library::start(shared, &name, arguments, false),
+		// sequences check the abort latch themselves when they are spawned
+		ScheduledAction::ActuateValve { .. } if *shared.abort_latched.lock().unwrap() => {
+			Err("the vehicle is aborted".to_owned())
+		},
+		ScheduledAction::ActuateValve { name, state } => {
+			handler::actuate_valve(&name, state, &shared.mappings, &shared.vehicle_state, command_tx);
+			Ok(())
+		},
+	};
+
+	let severity = match &result {
+		Ok(()) => Severity::Info,
+		Err(error) => {
+			fail!("Failed to execute '{}': {error}.", item.name);
+			Severity::Warning
+		},
+	};
+
+	events::emit(shared, severity, module_path!(), EventKind::ScheduledAction {
+		name: item.name,
+		offset: item.offset,
+		error: result.err(),
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn schedule(offsets: &[f64]) -> Countdown {
+		let mut countdown = Countdown::default();
+
+		for offset in offsets {
+			let item = ScheduledItem {
+				name: format_time(*offset),
+				offset: *offset,
+				action: ScheduledAction::ActuateValve { name: "valve".to_owned(), state: ValveState::Open },
+			};
+
+			countdown.schedule.push((item, false));
+		}
+
+		countdown
+	}
+
+	fn offsets(items: Vec<ScheduledItem>) -> Vec<f64> {
+		items.into_iter().map(|item| item.offset).collect()
+	}
+
+	#[test]
+	fn start_skips_items_before_the_start_time() {
+		let mut countdown = schedule(&[-60.0, -30.0, -10.0, -5.0]);
+		countdown.start(-10.0).unwrap();
+
+		assert_eq!(offsets(countdown.due()), vec![-10.0]);
+		assert!(countdown.due().is_empty());
+	}
+
+	#[test]
+	fn nothing_is_due_while_holding() {
+		let mut countdown = schedule(&[-10.0, -5.0]);
+		countdown.start(-7.0).unwrap();
+
+		assert_eq!(countdown.hold().map(f64::round), Some(-7.0));
+		assert!(countdown.due().is_empty());
+		assert!(countdown.hold().is_none());
+	}
+
+	#[test]
+	fn resume_continues_from_the_hold() {
+		let mut countdown = schedule(&[-10.0, -5.0, 0.0]);
+		countdown.start(-5.0).unwrap();
+		assert_eq!(offsets(countdown.due()), vec![-5.0]);
+
+		let held = countdown.hold().unwrap();
+		assert_eq!(countdown.resume(), Ok(held));
+		assert!(countdown.resume().is_err());
+
+		// the item at T-0 is still ahead, and the one already executed isn't repeated
+		assert!(countdown.due().is_empty());
+
+		countdown.state = ClockState::Counting { t_zero: t_zero_at(0.0).unwrap() };
+		assert_eq!(offsets(countdown.due()), vec![0.0]);
+	}
+
+	#[test]
+	fn recycle_rearms_items_from_the_recycled_time() {
+		let mut countdown = schedule(&[-60.0, -30.0, -10.0]);
+		countdown.start(-60.0).unwrap();
+		countdown.state = ClockState::Counting { t_zero: t_zero_at(-5.0).unwrap() };
+		assert_eq!(offsets(countdown.due()), vec![-60.0, -30.0, -10.0]);
+
+		// recycling to an earlier time repeats only what is scheduled from then on
+		countdown.recycle(-30.0).unwrap();
+		assert!(countdown.due().is_empty());
+		assert_eq!(countdown.resume().map(f64::round), Ok(-30.0));
+		assert_eq!(offsets(countdown.due()), vec![-30.0]);
+
+		// recycling to a later time never executes what it skipped
+		let mut countdown = schedule(&[-60.0, -30.0, -10.0]);
+		countdown.start(-120.0).unwrap();
+		countdown.recycle(-20.0).unwrap();
+		countdown.resume().unwrap();
+		countdown.state = ClockState::Counting { t_zero: t_zero_at(-10.0).unwrap() };
+		assert_eq!(offsets(countdown.due()), vec![-10.0]);
+	}
+
+	#[test]
+	fn out_of_range_times_are_refused() {
+		let mut countdown = schedule(&[-10.0]);
+
+		assert!(countdown.start(-f64::from(u32::MAX) * 1e12).is_err());
+		assert!(countdown.recycle(-1e300).is_err());
+		assert!(countdown.clock().is_none());
+	}
+}
@@ -0,0 +1,269 @@
+use crate::{handler, operator::{self, FlightReport}, state::SharedState, validation::{self, ValidationIssue}, MAX_DRY_RUN_ACTIONS};
+use common::{comm::{CompositeValveState, Sequence, ValveState, VehicleState}, sequence::{AbortError, DeviceAction}};
+use jeflog::{pass, warn};
+use pyo3::{IntoPy, PyObject, PyResult, Python, ToPyObject, types::PyNone};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, thread, time::Duration};
+
+/// Something a sequence would have done had it not been a dry run.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SimulatedAction {
+	/// Simulated time since the sequence started, which only advances while it waits.
+	pub elapsed: Duration,
+
+	pub kind: SimulatedActionKind,
+}
+
+/// What a sequence would have done in a dry run.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum SimulatedActionKind {
+	ReadSensor {
+		name: String,
+		value: Option<f64>,
+	},
+
+	ReadValveState {
+		name: String,
+		state: Option<ValveState>,
+	},
+
+	ActuateValve {
+		name: String,
+		state: ValveState,
+	},
+
+	Sleep(Duration),
+
+	/// Waited on a condition, which is assumed never to become true during the wait if it
+	/// wasn't already.
+	WaitUntil {
+		condition: String,
+		met: bool,
+	},
+
+	/// Started a sequence from the library on its own thread, which is not simulated.
+	StartSequence(String),
+
+	/// Calibrated a sensor, which is not simulated, so no offset is applied.
+	Calibrate {
+		name: String,
+		window: Duration,
+		expected: f64,
+	},
+
+	/// Released the sequence's claim on a valve.
+	ReleaseValve(String),
+
+	Abort,
+}
+
+/// The result of dry-running a sequence.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DryRunReport {
+	pub name: String,
+	pub issues: Vec<ValidationIssue>,
+	pub actions: Vec<SimulatedAction>,
+
+	/// Whether the sequence aborted, or was cut off after `MAX_DRY_RUN_ACTIONS` actions.
+	pub aborted: bool,
+
+	/// The exception and traceback if the sequence raised one.
+	pub traceback: Option<String>,
+}
+
+/// A dry run in progress on the current thread.
+struct Simulation {
+	vehicle_state: VehicleState,
+	elapsed: Duration,
+	actions: Vec<SimulatedAction>,
+}
+
+thread_local! {
+	/// The dry run being executed on the current thread, if any.
+	static SIMULATION: RefCell<Option<Simulation>> = const { RefCell::new(None) };
+}
+
+/// Spawns a thread which dry-runs a sequence against a copy of the current vehicle state,
+/// then reports every action it would have taken to the operator.
+pub fn start(shared: &SharedState, sequence: Sequence) {
+	let shared = shared.clone();
+
+	thread::spawn(move || {
+		let report = run(&shared, sequence);
+		pass!("Dry run of sequence '{}' took {} actions.", report.name, report.actions.len());
+		operator::report(&shared, &FlightReport::DryRun(report));
+	});
+}
+
+/// Dry-runs a sequence on the current thread, which must not be running a sequence.
+fn run(shared: &SharedState, sequence: Sequence) -> DryRunReport {
+	let issues = validation::validate(shared, &sequence.script);
+
+	let simulation = Simulation {
+		vehicle_state: shared.vehicle_state.lock().unwrap().clone(),
+		elapsed: Duration::ZERO,
+		actions: Vec::new(),
+	};
+
+	SIMULATION.with(|current| *current.borrow_mut() = Some(simulation));
+
+	let result = Python::with_gil(|py| -> PyResult<()> {
+		py.run(&sequence.script, Some(handler::sequence_globals(py, None)?), None)
+	});
+
+	let simulation = SIMULATION
+		.with(|current| current.borrow_mut().take())
+		.expect("dry run simulation was removed while running");
+
+	let (aborted, traceback) = match result {
+		Ok(()) => (false, None),
+		Err(error) => Python::with_gil(|py| match error.is_instance_of::<AbortError>(py) {
+			true => (true, None),
+			false => (false, Some(handler::format_exception(py, &error))),
+		}),
+	};
+
+	DryRunReport {
+		name: sequence.name,
+		issues,
+		actions: simulation.actions,
+		aborted,
+		traceback,
+	}
+}
+
+/// Whether the current thread is dry-running a sequence.
+pub fn active() -> bool {
+	SIMULATION.with(|current| current.borrow().is_some())
+}
+
+/// Records an action in the current dry run, returning false once the dry run has taken
+/// too many actions and should be stopped.
+fn record(simulation: &mut Simulation, kind: SimulatedActionKind) -> bool {
+	if simulation.actions.len() >= MAX_DRY_RUN_ACTIONS {
+		return false;
+	}
+
+	simulation.actions.push(SimulatedAction { elapsed: simulation.elapsed, kind });
+	true
+}
+
+/// Simulates a device action if the current thread is dry-running a sequence, returning
+/// what the device handler should return.
+pub fn handle(device: &str, action: &DeviceAction) -> Option<PyObject> {
+	SIMULATION.with(|current| {
+		let mut current = current.borrow_mut();
+		let simulation = current.as_mut()?;
+
+		let (kind, result) = Python::with_gil(|py| match action {
+			DeviceAction::ReadSensor => {
+				let measurement = simulation.vehicle_state.sensor_readings.get(device).cloned();
+
+				let kind = SimulatedActionKind::ReadSensor {
+					name: device.to_owned(),
+					value: measurement.as_ref().map(|measurement| measurement.value),
+				};
+
+				(kind, measurement.map_or(PyNone::get(py).to_object(py), |measurement| measurement.into_py(py)))
+			},
+			DeviceAction::ReadValveState => {
+				let state = simulation.vehicle_state.valve_states
+					.get(device)
+					.map(|state| state.actual);
+
+				let kind = SimulatedActionKind::ReadValveState { name: device.to_owned(), state };
+				(kind, state.map_or(PyNone::get(py).to_object(py), |state| state.to_string().into_py(py)))
+			},
+			DeviceAction::ActuateValve { state } => {
+				// simulated valves reach their commanded state immediately
+				simulation.vehicle_state.valve_states.insert(device.to_owned(), CompositeValveState {
+					commanded: *state,
+					actual: *state,
+				});
+
+				let kind = SimulatedActionKind::ActuateValve { name: device.to_owned(), state: *state };
+				(kind, PyNone::get(py).to_object(py))
+			},
+			DeviceAction::Abort => (SimulatedActionKind::Abort, PyNone::get(py).to_object(py)),
+		});
+
+		let stop = matches!(action, DeviceAction::Abort) || !record(simulation, kind);
+
+		if stop {
+			Python::with_gil(|py| {
+				// the device handler can't return an error, so raise it once control returns to Python
+				if let Err(error) = handler::raise_in_current_thread(py, py.get_type::<AbortError>()) {
+					warn!("Failed to stop dry run: {error}");
+				}
+			});
+		}
+
+		Some(result)
+	})
+}
+
+/// Records an action which is skipped rather than simulated if the current thread is
+/// dry-running a sequence, advancing simulated time by however long the action would have
+/// blocked. Returns whether it was.
+pub fn skip(kind: SimulatedActionKind, duration: Duration) -> PyResult<bool> {
+	SIMULATION.with(|current| {
+		let mut current = current.borrow_mut();
+
+		let Some(simulation) = current.as_mut() else {
+			return Ok(false);
+		};
+
+		if !record(simulation, kind) {
+			return Err(AbortError::new_err("dry run took too many actions"));
+		}
+
+		simulation.elapsed += duration;
+		Ok(true)
+	})
+}
+
+/// Simulates sleeping if the current thread is dry-running a sequence, returning whether it was.
+pub fn sleep(duration: Duration) -> PyResult<bool> {
+	SIMULATION.with(|current| {
+		let mut current = current.borrow_mut();
+
+		let Some(simulation) = current.as_mut() else {
+			return Ok(false);
+		};
+
+		if !record(simulation, SimulatedActionKind::Sleep(duration)) {
+			return Err(AbortError::new_err("dry run took too many actions"));
+		}
+
+		simulation.elapsed += duration;
+		Ok(true)
+	})
+}
+
+/// Evaluates a condition against the simulated vehicle state if the current thread is
+/// dry-running a sequence, returning whether it was met.
+///
+/// An unmet condition waits out its whole timeout, as the simulated vehicle state only
+/// changes when the sequence actuates a valve.
+pub fn wait_until(condition: &str, timeout: Duration, met: impl Fn(&VehicleState) -> bool) -> PyResult<Option<bool>> {
+	SIMULATION.with(|current| {
+		let mut current = current.borrow_mut();
+
+		let Some(simulation) = current.as_mut() else {
+			return Ok(None);
+		};
+
+		let met = met(&simulation.vehicle_state);
+		let kind = SimulatedActionKind::WaitUntil { condition: condition.to_owned(), met };
+
+		if !record(simulation, kind) {
+			return Err(AbortError::new_err("dry run took too many actions"));
+		}
+
+		if !met {
+			simulation.elapsed += timeout;
+		}
+
+		Ok(Some(met))
+	})
+}
@@ -0,0 +1,183 @@
+use crate::{operator::{self, FlightReport, LimitSeverity}, recorder::{self, Entry}, state::SharedState, EVENT_BACKLOG_LENGTH, EVENT_RETRY_PERIOD};
+use common::comm::{BoardId, ValveState};
+use jeflog::warn;
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, sync::mpsc::{self, Receiver, RecvTimeoutError, Sender}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+/// How urgently an event should be brought to the operator's attention.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum Severity {
+	Info,
+	Warning,
+	Critical,
+}
+
+/// Something noteworthy which happened on the flight computer.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Event {
+	pub severity: Severity,
+
+	/// Path of the module which emitted the event.
+	pub source: String,
+
+	/// Time since the flight computer started.
+	pub elapsed: Duration,
+
+	/// Time since the Unix epoch, for correlating with other systems.
+	pub time: Duration,
+
+	pub kind: EventKind,
+}
+
+/// What happened in an event.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum EventKind {
+	/// The program state transitioned, as displayed.
+	Transition(String),
+
+	/// The vehicle was aborted.
+	Abort,
+
+	/// The operator acknowledged an abort and re-armed the vehicle.
+	Rearmed,
+
+	/// A board identified itself to the switchboard.
+	BoardConnected(BoardId),
+
+	/// A board stopped sending data for longer than it is allowed to.
+	BoardLost(BoardId),
+
+	/// A command could not be sent to a board.
+	CommandFailed {
+		board_id: BoardId,
+		reason: String,
+	},
+
+	/// A trigger's condition was met and its script was run.
+	TriggerFired(String),
+
+	/// A trigger's condition raised an exception, deactivating the trigger.
+	TriggerFailed {
+		name: String,
+		error: String,
+	},
+
+	/// A sequence raised an exception.
+	SequenceFailed {
+		name: String,
+		traceback: String,
+	},
+
+	/// A red-line limit tripped.
+	LimitTripped {
+		text_id: String,
+		severity: LimitSeverity,
+		value: f64,
+	},
+
+	/// A sequence tried to actuate a valve claimed by another running sequence.
+	ValveConflict {
+		name: String,
+		owner: String,
+	},
+
+	/// A stopped or aborted sequence took longer than expected to exit.
+	SlowStop {
+		name: String,
+		latency: Duration,
+	},
+
+	/// The countdown clock or its schedule was changed, as displayed.
+	Countdown(String),
+
+	/// A scheduled action came due and was executed, unless it failed with the given error.
+	ScheduledAction {
+		name: String,
+		offset: f64,
+		error: Option<String>,
+	},
+
+	/// A valve's actual state disagreed with its commanded state for longer than its settle time.
+	ValveMismatch {
+		name: String,
+		commanded: ValveState,
+		actual: ValveState,
+	},
+}
+
+pub type EventSender = Sender<Event>;
+
+/// Spawns the thread which records events and delivers them to the server, buffering them
+/// while the server is not connected.
+pub fn start(shared: &SharedState) {
+	let (event_tx, event_rx) = mpsc::channel::<Event>();
+	*shared.events.lock().unwrap() = Some(event_tx);
+
+	shared.spawn("events", deliver(shared.clone(), event_rx));
+}
+
+/// Records every event received and delivers it to the server once connected.
+fn deliver(shared: SharedState, events: Receiver<Event>) -> impl FnOnce() {
+	move || {
+		let mut backlog = VecDeque::new();
+		let mut dropped = 0;
+
+		loop {
+			match events.recv_timeout(EVENT_RETRY_PERIOD) {
+				Ok(event) => {
+					recorder::record(&shared, Entry::Event(event.clone()));
+					backlog.push_back(event);
+
+					if backlog.len() > EVENT_BACKLOG_LENGTH {
+						backlog.pop_front();
+						dropped += 1;
+					}
+				},
+				// retry delivering the backlog periodically in case the server has reconnected
+				Err(RecvTimeoutError::Timeout) => {},
+				Err(RecvTimeoutError::Disconnected) => break,
+			}
+
+			if shared.server_link.lock().unwrap().is_none() {
+				continue;
+			}
+
+			if dropped > 0 {
+				warn!("Dropped {dropped} events which could not be delivered before the backlog filled.");
+				dropped = 0;
+			}
+
+			while let Some(event) = backlog.front() {
+				if operator::send_report(&shared, &FlightReport::Event(event.clone())).is_err() {
+					break;
+				}
+
+				backlog.pop_front();
+			}
+		}
+	}
+}
+
+/// Emits an event from the given module, to be recorded and delivered to the server.
+pub fn emit(shared: &SharedState, severity: Severity, source: &str, kind: EventKind) {
+	let events = shared.events.lock().unwrap();
+
+	let Some(events) = &*events else {
+		return;
+	};
+
+	let time = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default();
+
+	let event = Event {
+		severity,
+		source: source.to_owned(),
+		elapsed: shared.started.elapsed(),
+		time,
+		kind,
+	};
+
+	// the event thread only stops if the flight computer is shutting down
+	let _ = events.send(event);
+}
@@ -1,35 +1,623 @@
-use crate::state::SharedState;
+use crate::{countdown::CountdownClock, inventory::RawDataPoint, operator::MappingOptions, packet::{self, PacketHeader, MAX_FRAGMENT_SIZE, PROTOCOL_VERSION}, persistence, staleness, state::SharedState, units::UnitOfMeasure, DESTINATIONS_FILE, TELEMETRY_PACKET_SIZE};
+use common::comm::{CompositeValveState, Measurement, VehicleState};
 use jeflog::fail;
-use std::{net::UdpSocket, thread, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, mem, net::{SocketAddr, UdpSocket}, thread, time::{Duration, Instant}};
 
-pub fn forward_vehicle_state(shared: &SharedState) -> impl Fn() -> () {
-	let server_address = shared.server_address.clone();
-	let vehicle_state = shared.vehicle_state.clone();
+/// The longest the forwarder sleeps between checks for new destinations and configuration.
+const MAX_FORWARDER_SLEEP: Duration = Duration::from_millis(100);
+
+/// How often and where vehicle state updates are forwarded.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct ForwarderConfig {
+	/// Time between consecutive vehicle state updates.
+	pub period: Duration,
+
+	/// UDP port on the server which updates are sent to.
+	pub port: u16,
+
+	/// Time between full keyframes, which are sent in place of a delta so that a newly
+	/// connected server can synchronize.
+	pub keyframe_period: Duration,
+}
+
+/// An additional destination which telemetry is forwarded to, independent of the
+/// operator connection, such as a backup ground station, data logger or multicast group.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TelemetryDestination {
+	/// Unique name identifying this destination.
+	pub name: String,
+
+	/// Address which telemetry is sent to, which may be a multicast group.
+	pub address: SocketAddr,
+
+	/// Time between consecutive vehicle state updates.
+	pub period: Duration,
+
+	/// Time between full keyframes.
+	pub keyframe_period: Duration,
+
+	/// Names of the readings and valves forwarded to this destination, or all if not set.
+	pub channels: Option<Vec<String>>,
+}
+
+/// Loads the persisted telemetry destinations, or none if they cannot be read.
+pub fn load_destinations() -> Vec<TelemetryDestination> {
+	persistence::load(DESTINATIONS_FILE).unwrap_or_default()
+}
+
+/// Persists the given telemetry destinations so they are restored on restart.
+pub fn persist_destinations(destinations: &[TelemetryDestination]) -> Result<(), String> {
+	persistence::persist(DESTINATIONS_FILE, destinations)
+}
+
+/// Whether a reading or valve is included in the given channel subset. Valve voltage and
+/// current readings are included with their valve.
+fn includes(channels: Option<&[String]>, name: &str) -> bool {
+	let Some(channels) = channels else {
+		return true;
+	};
+
+	let valve = name
+		.strip_suffix("_V")
+		.or_else(|| name.strip_suffix("_I"));
+
+	channels
+		.iter()
+		.any(|channel| channel == name || Some(channel.as_str()) == valve)
+}
+
+/// A frame of telemetry as it is sent to the server.
+#[derive(Serialize)]
+enum TelemetryFrame<'a> {
+	/// The full vehicle state.
+	Keyframe(&'a VehicleStateUpdate),
+
+	/// Only what has changed since the previous frame.
+	Delta(&'a VehicleStateDelta),
+
+	/// The latest values of channels which match no mapping, sent in raw passthrough mode.
+	Raw(&'a [RawDataPoint]),
+}
+
+/// The changes to the vehicle state since the previous frame.
+#[derive(Serialize)]
+struct VehicleStateDelta {
+	/// Sensor readings which changed and were due to be sent at their downlink rate.
+	sensor_readings: Vec<(String, Measurement)>,
+
+	/// Valve states which changed.
+	valve_states: Vec<(String, CompositeValveState)>,
+
+	/// Names of every sensor reading which is currently stale.
+	stale_readings: Vec<String>,
+
+	/// Converted values of the sensor readings included in this delta.
+	converted_readings: Vec<ConvertedReading>,
+
+	/// The countdown clock when the delta was captured, if a countdown is in progress.
+	countdown: Option<CountdownClock>,
+
+	/// Whether the abort latch is set.
+	abort_latched: bool,
+}
+
+/// Tracks what was last sent to the server so that deltas only carry changes.
+struct DeltaTracker {
+	/// The last value and send time of each sensor reading.
+	sensors: HashMap<String, (f64, Instant)>,
+
+	/// The last sent state of each valve.
+	valves: HashMap<String, CompositeValveState>,
+
+	/// The most recently computed delta, kept to reuse its allocations.
+	delta: VehicleStateDelta,
+}
+
+/// A vehicle state update as it is forwarded to the control server.
+///
+/// A single update is kept by the forwarder and refreshed in place so that the
+/// allocations made for previous updates are reused.
+#[derive(Serialize)]
+struct VehicleStateUpdate {
+	vehicle_state: VehicleState,
+
+	/// Names of the sensor readings which have not been updated within their staleness threshold.
+	stale_readings: Vec<String>,
+
+	/// Readings converted to the unit system configured in their mapping options.
+	converted_readings: Vec<ConvertedReading>,
+
+	/// The countdown clock when the update was captured, if a countdown is in progress.
+	countdown: Option<CountdownClock>,
+
+	/// Whether the vehicle has aborted and is waiting to be re-armed by the operator.
+	abort_latched: bool,
+}
+
+/// A sensor reading converted to a unit which `common::comm::Unit` cannot represent.
+#[derive(Clone, Serialize)]
+struct ConvertedReading {
+	text_id: String,
+	value: f64,
+	unit: UnitOfMeasure,
+}
+
+impl VehicleStateUpdate {
+	fn new() -> Self {
+		VehicleStateUpdate {
+			vehicle_state: VehicleState::new(),
+			stale_readings: Vec::new(),
+			converted_readings: Vec::new(),
+			countdown: None,
+			abort_latched: false,
+		}
+	}
+
+	/// Copies the latest shared state into this update, holding each lock only for the copy.
+	/// If `channels` is set, only those readings and valves are kept.
+	fn refresh(&mut self, shared: &SharedState, channels: Option<&[String]>) {
+		let vehicle_state = shared.vehicle_state.lock().unwrap();
+		copy_channels(&mut self.vehicle_state.sensor_readings, &vehicle_state.sensor_readings, channels);
+		copy_channels(&mut self.vehicle_state.valve_states, &vehicle_state.valve_states, channels);
+		drop(vehicle_state);
+
+		self.countdown = shared.countdown.lock().unwrap().clock();
+		self.abort_latched = *shared.abort_latched.lock().unwrap();
+
+		let reading_timestamps = shared.reading_timestamps.lock().unwrap();
+		refill(
+			&mut self.stale_readings,
+			staleness::stale_readings(&reading_timestamps, shared.clock.now()).filter(|name| includes(channels, name)),
+			|name| name.clone(),
+			|name, existing| existing.clone_from(name),
+		);
+		drop(reading_timestamps);
+
+		// timestamps are kept in no particular order, so sort the names to compare updates
+		self.stale_readings.sort_unstable();
+
+		// readings outside of the channel subset were already removed from the vehicle state
+		let mapping_options = shared.mapping_options.lock().unwrap();
+		let converted = convert_readings(&self.vehicle_state, &mapping_options);
+		refill(
+			&mut self.converted_readings,
+			converted,
+			|(text_id, value, unit)| ConvertedReading { text_id: text_id.clone(), value, unit },
+			|(text_id, value, unit), existing| {
+				existing.text_id.clone_from(text_id);
+				existing.value = value;
+				existing.unit = unit;
+			},
+		);
+	}
+}
+
+/// Overwrites `target` with the items of `source`, overwriting the existing elements of
+/// `target` in place so their allocations are reused and only creating new elements as needed.
+fn refill<T, I>(target: &mut Vec<T>, source: impl Iterator<Item = I>, create: impl Fn(I) -> T, overwrite: impl Fn(I, &mut T)) {
+	let mut length = 0;
+
+	for item in source {
+		if length < target.len() {
+			overwrite(item, &mut target[length]);
+		} else {
+			target.push(create(item));
+		}
+
+		length += 1;
+	}
+
+	target.truncate(length);
+}
+
+/// Overwrites `target` with the entries of `source` which are in `channels`, updating
+/// existing entries in place so that the map and its keys are only allocated for new channels.
+fn copy_channels<V: Copy>(target: &mut HashMap<String, V>, source: &HashMap<String, V>, channels: Option<&[String]>) {
+	target.retain(|name, _| source.contains_key(name) && includes(channels, name));
+
+	for (name, value) in source {
+		if let Some(existing) = target.get_mut(name) {
+			*existing = *value;
+		} else if includes(channels, name) {
+			target.insert(name.clone(), *value);
+		}
+	}
+}
+
+/// Converts every reading with a telemetry unit system configured into that system.
+fn convert_readings<'a>(vehicle_state: &'a VehicleState, mapping_options: &'a HashMap<String, MappingOptions>) -> impl Iterator<Item = (&'a String, f64, UnitOfMeasure)> {
+	mapping_options
+		.iter()
+		.filter_map(|(text_id, options)| {
+			let system = options.telemetry_units?;
+			let measurement = vehicle_state.sensor_readings.get(text_id)?;
+
+			let unit = UnitOfMeasure::from(measurement.unit);
+			let target = unit.in_system(system);
+
+			// the target is always the same dimension, so this conversion cannot fail
+			let value = unit.convert(measurement.value, target).ok()?;
+			Some((text_id, value, target))
+		})
+}
+
+impl DeltaTracker {
+	fn new() -> Self {
+		DeltaTracker {
+			sensors: HashMap::new(),
+			valves: HashMap::new(),
+			delta: VehicleStateDelta {
+				sensor_readings: Vec::new(),
+				valve_states: Vec::new(),
+				stale_readings: Vec::new(),
+				converted_readings: Vec::new(),
+				countdown: None,
+				abort_latched: false,
+			},
+		}
+	}
+
+	/// Records that the full update was sent as a keyframe.
+	fn synchronize(&mut self, update: &VehicleStateUpdate) {
+		let now = Instant::now();
+
+		for (name, measurement) in &update.vehicle_state.sensor_readings {
+			if let Some(existing) = self.sensors.get_mut(name) {
+				*existing = (measurement.value, now);
+			} else {
+				self.sensors.insert(name.clone(), (measurement.value, now));
+			}
+		}
+
+		self.valves.clone_from(&update.vehicle_state.valve_states);
+		self.delta.stale_readings.clone_from(&update.stale_readings);
+	}
+
+	/// Computes the changes in `update` since the previous frame, recording them as sent.
+	/// Returns `None` if nothing changed.
+	fn delta(&mut self, update: &VehicleStateUpdate, mapping_options: &HashMap<String, MappingOptions>) -> Option<&VehicleStateDelta> {
+		let now = Instant::now();
+		let sensors = &mut self.sensors;
+
+		let changed_readings = update.vehicle_state.sensor_readings
+			.iter()
+			.filter(|(name, measurement)| {
+				let period = mapping_options
+					.get(*name)
+					.and_then(|options| options.downlink_period)
+					.unwrap_or_default();
+
+				match sensors.get_mut(*name) {
+					Some((_, sent)) if now - *sent < period => false,
+					Some((value, _)) if *value == measurement.value => false,
+					Some(existing) => {
+						*existing = (measurement.value, now);
+						true
+					},
+					None => {
+						sensors.insert((*name).clone(), (measurement.value, now));
+						true
+					},
+				}
+			});
+
+		refill(
+			&mut self.delta.sensor_readings,
+			changed_readings,
+			|(name, measurement)| (name.clone(), *measurement),
+			|(name, measurement), existing| {
+				existing.0.clone_from(name);
+				existing.1 = *measurement;
+			},
+		);
+
+		let valves = &mut self.valves;
+
+		let changed_valves = update.vehicle_state.valve_states
+			.iter()
+			.filter(|(name, state)| {
+				if valves.get(*name).is_some_and(|sent| sent.commanded == state.commanded && sent.actual == state.actual) {
+					return false;
+				}
+
+				valves.insert((*name).clone(), **state);
+				true
+			});
+
+		refill(
+			&mut self.delta.valve_states,
+			changed_valves,
+			|(name, state)| (name.clone(), *state),
+			|(name, state), existing| {
+				existing.0.clone_from(name);
+				existing.1 = *state;
+			},
+		);
+
+		let stale_changed = self.delta.stale_readings != update.stale_readings;
+
+		if stale_changed {
+			self.delta.stale_readings.clone_from(&update.stale_readings);
+		}
+
+		// the clock itself changes every frame, so only starting, holding or stopping it is a change
+		let countdown_changed = self.delta.countdown.map(|clock| clock.holding) != update.countdown.map(|clock| clock.holding);
+		self.delta.countdown = update.countdown;
+
+		let latch_changed = self.delta.abort_latched != update.abort_latched;
+		self.delta.abort_latched = update.abort_latched;
+
+		if self.delta.sensor_readings.is_empty() && self.delta.valve_states.is_empty() && !stale_changed && !countdown_changed && !latch_changed {
+			return None;
+		}
+
+		let included = &self.delta.sensor_readings;
+
+		refill(
+			&mut self.delta.converted_readings,
+			update.converted_readings
+				.iter()
+				.filter(|converted| included.iter().any(|(name, _)| *name == converted.text_id)),
+			|converted| converted.clone(),
+			|converted, existing| existing.clone_from(converted),
+		);
+
+		Some(&self.delta)
+	}
+}
+
+/// The state of forwarding telemetry to a single destination.
+struct Downlink {
+	update: VehicleStateUpdate,
+	tracker: DeltaTracker,
+	last_keyframe: Option<Instant>,
+
+	/// When the next update is due.
+	next: Instant,
+
+	counter: PacketCounter,
+}
+
+impl Downlink {
+	fn new() -> Self {
+		Downlink {
+			update: VehicleStateUpdate::new(),
+			tracker: DeltaTracker::new(),
+			last_keyframe: None,
+			next: Instant::now(),
+			counter: PacketCounter::default(),
+		}
+	}
+
+	/// Sends a keyframe or delta to `address` if one is due, scheduling the next update a period later.
+	fn forward(&mut self, shared: &SharedState, socket: &UdpSocket, buffers: &mut Buffers, address: SocketAddr, rates: Rates, channels: Option<&[String]>) {
+		let now = Instant::now();
+
+		if now < self.next {
+			return;
+		}
+
+		// schedule against a deadline so that time spent sending doesn't skew the rate
+		self.next = (self.next + rates.period).max(now);
+		self.update.refresh(shared, channels);
+
+		let keyframe_due = self.last_keyframe
+			.map_or(true, |last| last.elapsed() >= rates.keyframe_period);
+
+		let mapping_options = shared.mapping_options.lock().unwrap();
+
+		let frame = if keyframe_due {
+			self.last_keyframe = Some(now);
+			self.tracker.synchronize(&self.update);
+			Some(TelemetryFrame::Keyframe(&self.update))
+		} else {
+			self.tracker
+				.delta(&self.update, &mapping_options)
+				.map(TelemetryFrame::Delta)
+		};
+
+		drop(mapping_options);
+
+		// nothing changed, so there is nothing to send
+		let Some(frame) = frame else {
+			return;
+		};
+
+		self.counter.send(shared, socket, buffers, address, &frame);
+	}
+}
+
+/// How often updates and keyframes are sent to a single destination.
+#[derive(Clone, Copy)]
+struct Rates {
+	period: Duration,
+	keyframe_period: Duration,
+}
+
+/// Numbers the packets and frames sent to a single destination.
+#[derive(Default)]
+struct PacketCounter {
+	/// Sequence number of the next packet sent.
+	sequence: u32,
+
+	/// Number of the next frame sent.
+	frame: u32,
+}
+
+impl PacketCounter {
+	/// Serializes and sends a frame to `address`.
+	///
+	/// Frames too large for a single packet are split into numbered fragments, so that no
+	/// datagram exceeds `TELEMETRY_PACKET_SIZE`.
+	fn send(&mut self, shared: &SharedState, socket: &UdpSocket, buffers: &mut Buffers, address: SocketAddr, frame: &TelemetryFrame) {
+		buffers.frame.clear();
+
+		buffers.frame = match postcard::to_extend(frame, mem::take(&mut buffers.frame)) {
+			Ok(serialized) => serialized,
+			Err(error) => {
+				fail!("Failed to serialize vehicle state with Postcard: {}.", error.to_string());
+				return;
+			}
+		};
+
+		let fragments = buffers.frame.len().div_ceil(MAX_FRAGMENT_SIZE).max(1);
+
+		let Ok(fragments) = u16::try_from(fragments) else {
+			fail!("Vehicle state of {} bytes is too large to send to \x1b[1m{address}\x1b[0m.", buffers.frame.len());
+			return;
+		};
+
+		let elapsed = shared.started.elapsed();
+		let frame_number = self.frame;
+		self.frame = self.frame.wrapping_add(1);
+
+		for (index, fragment) in buffers.frame.chunks(MAX_FRAGMENT_SIZE).enumerate() {
+			let header = PacketHeader {
+				version: PROTOCOL_VERSION,
+				sequence: self.sequence,
+				elapsed,
+				frame: frame_number,
+				fragment: index as u16,
+				fragments,
+			};
+
+			self.sequence = self.sequence.wrapping_add(1);
+
+			match packet::encode(&header, fragment, &mut buffers.packet) {
+				Ok(serialized) => {
+					if socket.send_to(serialized, address).is_err() {
+						fail!("Failed to send vehicle state update to \x1b[1m{address}\x1b[0m.");
+						return;
+					}
+				},
+				Err(error) => {
+					fail!("Failed to frame vehicle state update: {}.", error.to_string());
+					return;
+				}
+			}
+		}
+	}
+}
+
+/// Reusable buffers which telemetry is serialized into before sending.
+struct Buffers {
+	/// Holds a whole serialized frame, growing to fit the largest frame sent.
+	frame: Vec<u8>,
+
+	/// Holds a single packet.
+	packet: Vec<u8>,
+}
+
+/// Forwards telemetry to the server and every additional destination, each at its own rate.
+pub fn forward_vehicle_state(shared: &SharedState) -> impl Fn() {
+	let shared = shared.clone();
 
 	let socket = UdpSocket::bind("0.0.0.0:0")
 		.expect("failed to bind to UDP socket");
 
 	move || {
+		let mut buffers = Buffers {
+			frame: Vec::new(),
+			packet: vec![0; TELEMETRY_PACKET_SIZE],
+		};
+		let mut server = Downlink::new();
+		let mut downlinks: HashMap<String, Downlink> = HashMap::new();
+		let mut raw = Vec::new();
+		let mut last_raw = Instant::now();
+
 		loop {
-			if let Some(server_address) = *server_address.lock().unwrap() {
-				let vehicle_state = vehicle_state.lock().unwrap();
+			let config = *shared.forwarder_config.lock().unwrap();
+			let server_address = *shared.server_address.lock().unwrap();
 
-				// TODO: Change to something that doesn't allocate every iteration
-				match postcard::to_allocvec(&*vehicle_state) {
-					Ok(serialized) => {
-						let result = socket.send_to(&serialized, (server_address, 7201));
+			if let Some(server_address) = server_address {
+				let address = SocketAddr::new(server_address, config.port);
+				let rates = Rates { period: config.period, keyframe_period: config.keyframe_period };
+				server.forward(&shared, &socket, &mut buffers, address, rates, None);
 
-						if result.is_err() {
-							fail!("Failed to send vehicle state update to server at \x1b[1m{server_address}:7201\x1b[0m.");
-						}
-					},
-					Err(error) => {
-						fail!("Failed to serialize vehicle state with Postcard: {}.", error.to_string());
+				// unmapped channels are only forwarded to the server, at the same rate as the vehicle state
+				let raw_passthrough = *shared.raw_passthrough.lock().unwrap();
+
+				if raw_passthrough && last_raw.elapsed() >= config.period {
+					let now = Instant::now();
+					shared.channel_inventory.lock().unwrap().unmapped_since(last_raw, &mut raw);
+					last_raw = now;
+
+					if !raw.is_empty() {
+						server.counter.send(&shared, &socket, &mut buffers, address, &TelemetryFrame::Raw(&raw));
 					}
 				}
 			}
 
-			thread::sleep(Duration::from_millis(10));
+			let destinations = shared.telemetry_destinations.lock().unwrap();
+
+			// forget the state of removed destinations so they start with a keyframe if re-added
+			downlinks.retain(|name, _| destinations.iter().any(|destination| destination.name == *name));
+
+			for destination in destinations.iter() {
+				if !downlinks.contains_key(&destination.name) {
+					downlinks.insert(destination.name.clone(), Downlink::new());
+				}
+
+				let downlink = downlinks.get_mut(&destination.name).unwrap();
+
+				downlink.forward(
+					&shared,
+					&socket,
+					&mut buffers,
+					destination.address,
+					Rates { period: destination.period, keyframe_period: destination.keyframe_period },
+					destination.channels.as_deref(),
+				);
+			}
+
+			drop(destinations);
+
+			// sleep until the next update is due to any destination
+			let next = downlinks
+				.values()
+				.map(|downlink| downlink.next)
+				.chain(server_address.map(|_| server.next))
+				.min()
+				.unwrap_or(Instant::now() + MAX_FORWARDER_SLEEP)
+				.min(Instant::now() + MAX_FORWARDER_SLEEP);
+
+			let now = Instant::now();
+
+			if next > now {
+				thread::sleep(next - now);
+			}
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn copying_channels_reuses_existing_allocations() {
+		let mut source = HashMap::from([("pt1".to_owned(), 1.0), ("pt2".to_owned(), 2.0)]);
+		let mut target = HashMap::new();
+
+		copy_channels(&mut target, &source, None);
+		let capacity = target.capacity();
+		let key = target.get_key_value("pt1").unwrap().0.as_ptr();
+
+		source.insert("pt1".to_owned(), 3.0);
+		copy_channels(&mut target, &source, None);
+
+		assert_eq!(target, source);
+		assert_eq!(target.capacity(), capacity);
+		assert_eq!(target.get_key_value("pt1").unwrap().0.as_ptr(), key);
+	}
+
+	#[test]
+	fn copying_channels_keeps_only_the_subset() {
+		let source = HashMap::from([("pt1".to_owned(), 1.0), ("pt2".to_owned(), 2.0)]);
+		let mut target = HashMap::from([("pt3".to_owned(), 3.0)]);
+
+		copy_channels(&mut target, &source, Some(&["pt2".to_owned()]));
+		assert_eq!(target, HashMap::from([("pt2".to_owned(), 2.0)]));
+	}
+}
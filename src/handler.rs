@@ -1,14 +1,18 @@
-use common::{comm::{BoardId, CompositeValveState, NodeMapping, SamControlMessage, ValveState, VehicleState}, sequence::{self, AbortError, DeviceAction}};
+use common::{comm::{BoardId, CompositeValveState, Measurement, NodeMapping, SamControlMessage, Sequence, ValveState, VehicleState}, sequence::{AbortError, DeviceAction}};
 use jeflog::{fail, warn};
-use pyo3::{types::PyNone, IntoPy, PyErr, PyObject, Python, ToPyObject};
-use std::{sync::{mpsc::Sender, Mutex}, thread};
+use pyo3::{exceptions::PyRuntimeError, ffi, types::{IntoPyDict, PyDict, PyNone, PyType}, AsPyPointer, IntoPy, PyErr, PyObject, PyResult, Python, ToPyObject};
+use std::{collections::HashMap, os::raw::{c_long, c_ulong}, ptr, sync::{mpsc::Sender, Mutex}, thread, time::Instant};
 
-use crate::state::SharedState;
+use crate::{builtins::{StaleReadingError, ValveConflictError}, countdown, dry_run, events::{self, EventKind, Severity}, library::Arguments, lifecycle::{self, SequenceStatus}, recorder, staleness::ReadingTimestamp, state::SharedState};
 
 pub fn create_device_handler(shared: SharedState, command_tx: Sender<(BoardId, SamControlMessage)>) -> impl Fn(&str, DeviceAction) -> PyObject {
 	let tx = command_tx.clone();
 
 	move |device, action| {
+		if let Some(result) = dry_run::handle(device, &action) {
+			return result;
+		}
+
 		let thread_id = thread::current().id();
 		let sequences = shared.sequences.lock().unwrap();
 		
@@ -27,9 +31,27 @@ pub fn create_device_handler(shared: SharedState, command_tx: Sender<(BoardId, S
 		drop(sequences);
 
 		match action {
-			DeviceAction::ReadSensor => read_sensor(device, &shared.vehicle_state),
+			DeviceAction::ReadSensor => read_sensor(device, &shared.vehicle_state, &shared.reading_timestamps, shared.clock.now()),
 			DeviceAction::ReadValveState => read_valve_state(device, &shared.vehicle_state),
 			DeviceAction::ActuateValve { state } => {
+				if let Err(owner) = claim_valve(&shared, device) {
+					fail!("Refused to actuate valve '{device}' because it is claimed by sequence '{owner}'.");
+
+					events::emit(&shared, Severity::Warning, module_path!(), EventKind::ValveConflict {
+						name: device.to_owned(),
+						owner: owner.clone(),
+					});
+
+					return Python::with_gil(|py| {
+						// the device handler can't return an error, so raise it once control returns to Python
+						if let Err(error) = raise_in_current_thread(py, py.get_type::<ValveConflictError>()) {
+							fail!("Failed to raise ValveConflictError: {error}");
+						}
+
+						PyNone::get(py).to_object(py)
+					});
+				}
+
 				actuate_valve(device, state, &shared.mappings, &shared.vehicle_state, &tx);
 				Python::with_gil(|py| PyNone::get(py).to_object(py))
 			},
@@ -41,22 +63,52 @@ pub fn create_device_handler(shared: SharedState, command_tx: Sender<(BoardId, S
 	}
 }
 
-fn read_sensor(name: &str, vehicle_state: &Mutex<VehicleState>) -> PyObject {
+/// Reads the latest measurement of a sensor, or `None` if it has never been read. Reading a
+/// stale sensor raises `StaleReadingError`.
+fn read_sensor(name: &str, vehicle_state: &Mutex<VehicleState>, reading_timestamps: &Mutex<HashMap<String, ReadingTimestamp>>, now: Instant) -> PyObject {
+	let measurement = fresh_measurement(name, vehicle_state, reading_timestamps, now);
+
+	Python::with_gil(move |py| {
+		match measurement {
+			Ok(measurement) => measurement
+				.map_or(
+					PyNone::get(py).to_object(py),
+					|m| m.into_py(py),
+				),
+			Err(_) => {
+				// the device handler can't return an error, so raise it once control returns to Python
+				if let Err(error) = raise_in_current_thread(py, py.get_type::<StaleReadingError>()) {
+					fail!("Failed to raise StaleReadingError: {error}");
+				}
+
+				PyNone::get(py).to_object(py)
+			},
+		}
+	})
+}
+
+/// Gets the latest measurement of a sensor, or `None` if it has never been read, failing
+/// with `StaleReadingError` if it is stale as of `now`.
+pub fn fresh_measurement(name: &str, vehicle_state: &Mutex<VehicleState>, reading_timestamps: &Mutex<HashMap<String, ReadingTimestamp>>, now: Instant) -> PyResult<Option<Measurement>> {
 	let vehicle_state = vehicle_state
 		.lock()
 		.unwrap();
 
-	let measurement = vehicle_state
-		.sensor_readings
-		.get(name);
+	let stale = reading_timestamps
+		.lock()
+		.unwrap()
+		.get(name)
+		.is_some_and(|timestamp| timestamp.is_stale(now));
 
-	Python::with_gil(move |py| {
-		measurement
-			.map_or(
-				PyNone::get(py).to_object(py),
-				|m| m.clone().into_py(py), 
-			)
-	})
+	if stale {
+		warn!("Sensor '{name}' was read by a sequence but its latest reading is stale.");
+		return Err(StaleReadingError::new_err(format!("the latest reading of sensor '{name}' is stale")));
+	}
+
+	Ok(vehicle_state
+		.sensor_readings
+		.get(name)
+		.cloned())
 }
 
 fn read_valve_state(name: &str, vehicle_state: &Mutex<VehicleState>) -> PyObject {
@@ -76,7 +128,7 @@ fn read_valve_state(name: &str, vehicle_state: &Mutex<VehicleState>) -> PyObject
 	})
 }
 
-fn actuate_valve(name: &str, state: ValveState, mappings: &Mutex<Vec<NodeMapping>>, vehicle_state: &Mutex<VehicleState>, command_tx: &Sender<(BoardId, SamControlMessage)>) {
+pub fn actuate_valve(name: &str, state: ValveState, mappings: &Mutex<Vec<NodeMapping>>, vehicle_state: &Mutex<VehicleState>, command_tx: &Sender<(BoardId, SamControlMessage)>) {
 	let mappings = mappings.lock().unwrap();
 
 	let Some(mapping) = mappings.iter().find(|m| m.text_id == name) else {
@@ -107,23 +159,237 @@ fn actuate_valve(name: &str, state: ValveState, mappings: &Mutex<Vec<NodeMapping
 	}
 }
 
+/// Claims a valve for the sequence running on the current thread, returning the name of
+/// the running sequence which already owns it if it is claimed by another.
+///
+/// The abort sequence overrides every claim.
+fn claim_valve(shared: &SharedState, name: &str) -> Result<(), String> {
+	let sequences = shared.sequences.lock().unwrap();
+
+	let Some(sequence) = sequences.get_by_right(&thread::current().id()) else {
+		return Ok(());
+	};
+
+	if sequence == "abort" {
+		return Ok(());
+	}
+
+	let mut valve_claims = shared.valve_claims.lock().unwrap();
+
+	match valve_claims.get(name) {
+		// claims of sequences which are no longer running have lapsed
+		Some(owner) if owner != sequence && sequences.contains_left(owner) => Err(owner.clone()),
+		Some(owner) if owner == sequence => Ok(()),
+		_ => {
+			valve_claims.insert(name.to_owned(), sequence.clone());
+			Ok(())
+		},
+	}
+}
+
+/// Releases the claim of the sequence running on the current thread on a valve, if it has one.
+pub fn release_valve(shared: &SharedState, name: &str) {
+	let sequences = shared.sequences.lock().unwrap();
+	let mut valve_claims = shared.valve_claims.lock().unwrap();
+
+	let owned = sequences
+		.get_by_right(&thread::current().id())
+		.is_some_and(|sequence| valve_claims.get(name) == Some(sequence));
+
+	if owned {
+		valve_claims.remove(name);
+	}
+}
+
+/// Raises an exception in the current Python thread the next time it executes bytecode.
+///
+/// Unlike restoring an error, this works from within a callback which can't return one.
+pub fn raise_in_current_thread(py: Python<'_>, exception: &PyType) -> PyResult<()> {
+	raise_in_thread(py, python_thread_id(py)?, exception)
+}
+
+/// The identifier of the current Python thread, as returned by `threading.get_ident`.
+pub fn python_thread_id(py: Python<'_>) -> PyResult<u64> {
+	py.import("threading")?
+		.call_method0("get_ident")?
+		.extract::<u64>()
+}
+
+/// Raises an exception of the given type in the Python thread with the given identifier, as
+/// returned by `threading.get_ident`, the next time that thread executes bytecode.
+pub fn raise_in_thread(_py: Python<'_>, thread_id: u64, exception: &PyType) -> PyResult<()> {
+	let ident = thread_ident(thread_id)?;
+
+	// holding the GIL, as proven by the token, is required to set an asynchronous exception
+	let raised = unsafe { ffi::PyThreadState_SetAsyncExc(ident, exception.as_ptr()) };
+
+	if raised == 0 {
+		return Err(PyRuntimeError::new_err(format!("no Python thread with identifier {thread_id}")));
+	}
+
+	// the exception was raised in more than one thread, which must be undone
+	if raised > 1 {
+		unsafe { ffi::PyThreadState_SetAsyncExc(ident, ptr::null_mut()) };
+		return Err(PyRuntimeError::new_err(format!("more than one Python thread has identifier {thread_id}")));
+	}
+
+	Ok(())
+}
+
+/// Cancels any exception raised in the Python thread with the given identifier which it
+/// has not executed far enough to see yet.
+pub fn cancel_in_thread(_py: Python<'_>, thread_id: u64) {
+	let Ok(ident) = thread_ident(thread_id) else {
+		return;
+	};
+
+	// holding the GIL, as proven by the token, is required to clear an asynchronous exception
+	unsafe { ffi::PyThreadState_SetAsyncExc(ident, ptr::null_mut()) };
+}
+
+/// Converts a thread identifier into the argument of `PyThreadState_SetAsyncExc`.
+///
+/// Identifiers are an `unsigned long`, which is only 32 bits on some platforms. The binding
+/// declares the argument as a `long`, so the identifier is reinterpreted rather than converted.
+fn thread_ident(thread_id: u64) -> PyResult<c_long> {
+	let ident = c_ulong::try_from(thread_id)
+		.map_err(|_| PyRuntimeError::new_err(format!("invalid Python thread identifier {thread_id}")))?;
+
+	Ok(ident as c_long)
+}
+
+/// Returns an `AbortError` if the current thread is not a running sequence, meaning that
+/// it was stopped or aborted and should not continue.
+///
+/// Only meaningful on a thread registered in `sequences`. Triggers are never registered, so
+/// callers which may run in a trigger must first check `lifecycle::in_sequence`.
+pub fn check_running(shared: &SharedState) -> PyResult<()> {
+	// a dry run isn't registered, but it has nothing to be stopped for
+	if dry_run::active() {
+		return Ok(());
+	}
+
+	let thread_id = thread::current().id();
+
+	if shared.sequences.lock().unwrap().get_by_right(&thread_id).is_none() {
+		return Err(AbortError::new_err("aborting sequence"));
+	}
+
+	Ok(())
+}
+
 pub fn abort(shared: &SharedState) {
+	// latch before anything else so that nothing new starts while the vehicle is safed
+	*shared.abort_latched.lock().unwrap() = true;
+
+	// flush before running the abort sequence so everything leading up to the abort is on disk
+	recorder::record(shared, recorder::Entry::Abort);
+	recorder::flush(shared);
+	events::emit(shared, Severity::Critical, module_path!(), EventKind::Abort);
+	countdown::hold(shared);
+
 	let abort_sequence = shared.abort_sequence
 		.lock()
 		.unwrap()
 		.clone();
 
+	// running sequences are stopped whether or not there is an abort sequence to safe the vehicle
+	let mut sequences = shared.sequences.lock().unwrap();
+
+	let interrupted: Vec<_> = sequences
+		.iter()
+		.filter_map(|(name, _)| lifecycle::interrupt(shared, name, SequenceStatus::Aborted))
+		.collect();
+
+	sequences.clear();
+
+	if abort_sequence.is_some() {
+		sequences.insert("abort".to_owned(), thread::current().id());
+	}
+
+	shared.valve_claims.lock().unwrap().clear();
+	drop(sequences);
+
+	lifecycle::halt(shared, interrupted);
+
 	let Some(sequence) = abort_sequence else {
 		warn!("Abort was called but no abort sequence is set.");
 		return;
 	};
 
-	let mut sequences = shared.sequences.lock().unwrap();
-	sequences.clear();
-	sequences.insert("abort".to_owned(), thread::current().id());
+	lifecycle::run(shared, sequence);
+}
+
+/// Clears the abort latch once the operator has acknowledged an abort, allowing sequences
+/// and triggers to run again. Fails if the abort sequence is still running.
+pub fn rearm(shared: &SharedState) -> Result<(), String> {
+	let sequences = shared.sequences.lock().unwrap();
+
+	if sequences.contains_left("abort") {
+		return Err("the abort sequence is still running".to_owned());
+	}
+
+	let mut abort_latched = shared.abort_latched.lock().unwrap();
+
+	if !*abort_latched {
+		return Err("the vehicle is not aborted".to_owned());
+	}
+
+	*abort_latched = false;
+	drop(abort_latched);
 	drop(sequences);
 
-	sequence::run(sequence);
+	events::emit(shared, Severity::Warning, module_path!(), EventKind::Rearmed);
+	Ok(())
+}
+
+/// Runs a sequence on the current thread in its own globals, emitting an event if it raises
+/// an exception other than being stopped or aborted. Library sequences are given their
+/// arguments in `args`.
+///
+/// Returns the formatted exception if the sequence failed, or `None` if it was stopped or
+/// aborted by an `AbortError`.
+pub fn run_sequence(shared: &SharedState, sequence: &Sequence, arguments: Option<&Arguments>) -> Result<(), Option<String>> {
+	let result = Python::with_gil(|py| {
+		let arguments = arguments.map(|arguments| arguments.iter().into_py_dict(py));
+
+		sequence_globals(py, arguments)
+			.and_then(|globals| py.run(&sequence.script, Some(globals), None))
+			.map_err(|error| (!error.is_instance_of::<AbortError>(py)).then(|| format_exception(py, &error)))
+	});
+
+	if let Err(Some(traceback)) = &result {
+		fail!("Sequence '{}' raised an exception:\n{traceback}", sequence.name);
+
+		events::emit(shared, Severity::Warning, module_path!(), EventKind::SequenceFailed {
+			name: sequence.name.clone(),
+			traceback: traceback.clone(),
+		});
+	}
+
+	result
+}
+
+/// Globals for a single run of a sequence: a copy of the usual globals, so that concurrent
+/// and successive runs never see each other's variables, with any arguments in `args`.
+pub fn sequence_globals<'py>(py: Python<'py>, arguments: Option<&'py PyDict>) -> PyResult<&'py PyDict> {
+	let globals = py.import("__main__")?.dict().copy()?;
+
+	if let Some(arguments) = arguments {
+		globals.set_item("args", arguments)?;
+	}
+
+	Ok(globals)
+}
+
+/// Formats a Python exception with its traceback, as Python would print it.
+pub fn format_exception(py: Python<'_>, error: &PyErr) -> String {
+	let traceback = error
+		.traceback(py)
+		.and_then(|traceback| traceback.format().ok())
+		.unwrap_or_default();
+
+	format!("{traceback}{error}")
 }
 
 
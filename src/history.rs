@@ -0,0 +1,195 @@
+use crate::SENSOR_HISTORY_LENGTH;
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+/// A single historical value of a sensor.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+	/// When the worker processed the value.
+	pub time: Instant,
+
+	/// The value in the reading's unit at the time.
+	pub value: f64,
+}
+
+/// Statistics over the samples within a window of time.
+#[derive(Clone, Copy, Debug)]
+pub struct Statistics {
+	pub min: f64,
+	pub max: f64,
+	pub mean: f64,
+	pub stddev: f64,
+}
+
+/// A fixed-size history of the most recent values of a sensor, oldest first.
+#[derive(Clone, Debug)]
+pub struct SensorHistory {
+	samples: VecDeque<Sample>,
+}
+
+impl SensorHistory {
+	/// Constructs an empty history which holds up to `SENSOR_HISTORY_LENGTH` samples.
+	pub fn new() -> Self {
+		SensorHistory { samples: VecDeque::with_capacity(SENSOR_HISTORY_LENGTH) }
+	}
+
+	/// Records a new sample, discarding the oldest if the history is full.
+	pub fn push(&mut self, time: Instant, value: f64) {
+		if self.samples.len() == SENSOR_HISTORY_LENGTH {
+			self.samples.pop_front();
+		}
+
+		self.samples.push_back(Sample { time, value });
+	}
+
+	/// Iterates over the samples received within `window` before `now`, oldest first.
+	pub fn window(&self, window: Duration, now: Instant) -> impl Iterator<Item = &Sample> {
+		self.samples
+			.iter()
+			.filter(move |sample| now.saturating_duration_since(sample.time) <= window)
+	}
+
+	/// Computes statistics over `window` before `now`, or `None` if there are no samples within it.
+	pub fn statistics(&self, window: Duration, now: Instant) -> Option<Statistics> {
+		let mut count = 0;
+		let mut min = f64::INFINITY;
+		let mut max = f64::NEG_INFINITY;
+		let mut sum = 0.0;
+
+		for sample in self.window(window, now) {
+			count += 1;
+			min = min.min(sample.value);
+			max = max.max(sample.value);
+			sum += sample.value;
+		}
+
+		if count == 0 {
+			return None;
+		}
+
+		let mean = sum / count as f64;
+
+		// population standard deviation, computed in a second pass for numerical stability
+		let variance = self.window(window, now)
+			.map(|sample| (sample.value - mean).powi(2))
+			.sum::<f64>() / count as f64;
+
+		Some(Statistics { min, max, mean, stddev: variance.sqrt() })
+	}
+
+	/// Computes the rate of change per second over `window` before `now` as the slope of a
+	/// least-squares fit, or `None` if there are fewer than two samples within it.
+	pub fn rate_of_change(&self, window: Duration, now: Instant) -> Option<f64> {
+		let samples = self.window(window, now).collect::<Vec<_>>();
+		let first = samples.first()?.time;
+
+		if samples.len() < 2 {
+			return None;
+		}
+
+		let n = samples.len() as f64;
+		let times = samples.iter().map(|sample| (sample.time - first).as_secs_f64());
+
+		let mean_time = times.clone().sum::<f64>() / n;
+		let mean_value = samples.iter().map(|sample| sample.value).sum::<f64>() / n;
+
+		let mut covariance = 0.0;
+		let mut variance = 0.0;
+
+		for (time, sample) in times.zip(&samples) {
+			covariance += (time - mean_time) * (sample.value - mean_value);
+			variance += (time - mean_time).powi(2);
+		}
+
+		// all samples were received at the same instant
+		if variance == 0.0 {
+			return None;
+		}
+
+		Some(covariance / variance)
+	}
+
+	/// Looks up the value of the sensor as it was `age` before `now`, interpolating linearly
+	/// between the samples on either side. Returns `None` if the history does not reach back that far.
+	pub fn value_at(&self, age: Duration, now: Instant) -> Option<f64> {
+		let target = now.checked_sub(age)?;
+		let after = self.samples.iter().position(|sample| sample.time >= target);
+
+		match after {
+			// the target is older than every sample
+			Some(0) => None,
+			Some(index) => {
+				let before = self.samples[index - 1];
+				let after = self.samples[index];
+				let span = (after.time - before.time).as_secs_f64();
+
+				if span == 0.0 {
+					return Some(after.value);
+				}
+
+				let fraction = (target - before.time).as_secs_f64() / span;
+				Some(before.value + (after.value - before.value) * fraction)
+			},
+			// the target is newer than every sample, so the latest value still holds
+			None => self.samples.back().map(|sample| sample.value),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Builds a history with one sample per second, the last taken at the returned instant.
+	fn history(values: &[f64]) -> (SensorHistory, Instant) {
+		let start = Instant::now();
+		let mut history = SensorHistory::new();
+
+		for (i, value) in values.iter().enumerate() {
+			history.push(start + Duration::from_secs(i as u64), *value);
+		}
+
+		(history, start + Duration::from_secs(values.len() as u64 - 1))
+	}
+
+	#[test]
+	fn statistics_cover_only_the_window() {
+		let (history, now) = history(&[100.0, 2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+
+		let statistics = history.statistics(Duration::from_secs(7), now).unwrap();
+		assert_eq!((statistics.min, statistics.max), (2.0, 9.0));
+		assert_eq!(statistics.mean, 5.0);
+		assert_eq!(statistics.stddev, 2.0);
+
+		assert!(history.statistics(Duration::from_secs(1), now + Duration::from_secs(5)).is_none());
+	}
+
+	#[test]
+	fn rate_of_change_is_the_slope_of_the_window() {
+		let (history, now) = history(&[0.0, 3.0, 6.0, 9.0]);
+		assert!((history.rate_of_change(Duration::from_secs(10), now).unwrap() - 3.0).abs() < 1e-9);
+
+		// a single sample has no slope
+		assert!(history.rate_of_change(Duration::ZERO, now).is_none());
+	}
+
+	#[test]
+	fn values_are_interpolated_between_samples() {
+		let (history, now) = history(&[0.0, 10.0, 20.0]);
+
+		assert_eq!(history.value_at(Duration::from_millis(500), now), Some(15.0));
+		assert_eq!(history.value_at(Duration::ZERO, now + Duration::from_secs(3)), Some(20.0));
+		assert_eq!(history.value_at(Duration::from_secs(5), now), None);
+	}
+
+	#[test]
+	fn oldest_samples_are_discarded_when_full() {
+		let mut history = SensorHistory::new();
+		let now = Instant::now();
+
+		for _ in 0..SENSOR_HISTORY_LENGTH + 1 {
+			history.push(now, 1.0);
+		}
+
+		assert_eq!(history.window(Duration::MAX, now).count(), SENSOR_HISTORY_LENGTH);
+	}
+}
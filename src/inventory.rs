@@ -0,0 +1,126 @@
+use common::comm::{BoardId, ChannelType, DataPoint};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+/// A data point which matched no mapping, as passed through in raw mode.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RawDataPoint {
+	pub board_id: BoardId,
+	pub channel: u32,
+	pub channel_type: ChannelType,
+	pub value: f64,
+}
+
+/// Everything seen on a single channel of a board, as reported to the operator.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelSummary {
+	pub board_id: BoardId,
+	pub channel: u32,
+	pub channel_type: ChannelType,
+
+	/// Whether any mapping currently corresponds to this channel.
+	pub mapped: bool,
+
+	/// How many data points have been received on this channel.
+	pub count: u64,
+
+	/// The most recently received value.
+	pub last_value: f64,
+
+	/// How long ago the most recent value was received.
+	pub age: Duration,
+}
+
+/// What has been seen on a single channel and channel type.
+#[derive(Clone, Debug)]
+struct ChannelEntry {
+	channel_type: ChannelType,
+	mapped: bool,
+	count: u64,
+	last_value: f64,
+	last_seen: Instant,
+}
+
+/// Every channel which each board has reported, whether mapped or not.
+#[derive(Debug, Default)]
+pub struct ChannelInventory {
+	/// Entries by board and channel. A channel rarely reports more than a couple of
+	/// channel types, so those are searched linearly.
+	boards: HashMap<BoardId, HashMap<u32, Vec<ChannelEntry>>>,
+}
+
+impl ChannelInventory {
+	/// Accounts for a data point received from a board.
+	pub fn observe(&mut self, board_id: &BoardId, data_point: &DataPoint, mapped: bool, received: Instant) {
+		// avoid cloning the board ID for every data point once the board is known
+		let channels = match self.boards.get_mut(board_id) {
+			Some(channels) => channels,
+			None => self.boards.entry(board_id.clone()).or_default(),
+		};
+
+		let entries = channels.entry(data_point.channel).or_default();
+
+		match entries.iter_mut().find(|entry| entry.channel_type == data_point.channel_type) {
+			Some(entry) => {
+				entry.mapped = mapped;
+				entry.count += 1;
+				entry.last_value = data_point.value;
+				entry.last_seen = received;
+			},
+			None => entries.push(ChannelEntry {
+				channel_type: data_point.channel_type,
+				mapped,
+				count: 1,
+				last_value: data_point.value,
+				last_seen: received,
+			}),
+		}
+	}
+
+	/// Every board which has reported any data point.
+	pub fn boards(&self) -> impl Iterator<Item = &BoardId> {
+		self.boards.keys()
+	}
+
+	/// Summarizes every channel seen as of `now`, ordered by board and channel.
+	pub fn summarize(&self, now: Instant) -> Vec<ChannelSummary> {
+		let mut summaries = Vec::new();
+
+		for (board_id, channels) in &self.boards {
+			for (channel, entries) in channels {
+				for entry in entries {
+					summaries.push(ChannelSummary {
+						board_id: board_id.clone(),
+						channel: *channel,
+						channel_type: entry.channel_type,
+						mapped: entry.mapped,
+						count: entry.count,
+						last_value: entry.last_value,
+						age: now.saturating_duration_since(entry.last_seen),
+					});
+				}
+			}
+		}
+
+		summaries.sort_by(|a, b| (&a.board_id, a.channel).cmp(&(&b.board_id, b.channel)));
+		summaries
+	}
+
+	/// Fills `raw` with the latest value of every unmapped channel received since `since`.
+	pub fn unmapped_since(&self, since: Instant, raw: &mut Vec<RawDataPoint>) {
+		raw.clear();
+
+		for (board_id, channels) in &self.boards {
+			for (channel, entries) in channels {
+				for entry in entries.iter().filter(|entry| !entry.mapped && entry.last_seen > since) {
+					raw.push(RawDataPoint {
+						board_id: board_id.clone(),
+						channel: *channel,
+						channel_type: entry.channel_type,
+						value: entry.last_value,
+					});
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,117 @@
+use crate::{lifecycle, persistence, state::SharedState, validation, LIBRARY_FILE};
+use common::comm::Sequence;
+use jeflog::warn;
+use pyo3::{exceptions::PyTypeError, types::{PyBool, PyFloat, PyLong, PyString}, FromPyObject, IntoPy, PyAny, PyObject, PyResult, Python, ToPyObject};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// A single argument passed to a library sequence, available to it in the `args` dictionary.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Argument {
+	Bool(bool),
+	Integer(i64),
+	Number(f64),
+	Text(String),
+}
+
+/// Arguments passed to a library sequence by name.
+pub type Arguments = BTreeMap<String, Argument>;
+
+impl ToPyObject for Argument {
+	fn to_object(&self, py: Python<'_>) -> PyObject {
+		match self {
+			Argument::Bool(value) => value.into_py(py),
+			Argument::Integer(value) => value.into_py(py),
+			Argument::Number(value) => value.into_py(py),
+			Argument::Text(value) => value.into_py(py),
+		}
+	}
+}
+
+impl<'source> FromPyObject<'source> for Argument {
+	fn extract(object: &'source PyAny) -> PyResult<Self> {
+		// bool is a subclass of int in Python, so it must be checked first
+		if let Ok(value) = object.downcast::<PyBool>() {
+			Ok(Argument::Bool(value.is_true()))
+		} else if object.is_instance_of::<PyLong>() {
+			Ok(Argument::Integer(object.extract()?))
+		} else if object.is_instance_of::<PyFloat>() {
+			Ok(Argument::Number(object.extract()?))
+		} else if object.is_instance_of::<PyString>() {
+			Ok(Argument::Text(object.extract()?))
+		} else {
+			Err(PyTypeError::new_err(format!("sequence arguments must be bool, int, float or str, not {}", object.get_type().name()?)))
+		}
+	}
+}
+
+/// Loads the persisted sequence library, or an empty one if none was persisted or it can't
+/// be loaded.
+pub fn load() -> HashMap<String, Sequence> {
+	persistence::load::<Vec<Sequence>>(LIBRARY_FILE)
+		.unwrap_or_default()
+		.into_iter()
+		.map(|sequence| (sequence.name.clone(), sequence))
+		.collect()
+}
+
+/// Persists the sequence library so it is restored on restart.
+fn persist(library: &HashMap<String, Sequence>) {
+	let sequences: Vec<&Sequence> = library.values().collect();
+
+	if let Err(error) = persistence::persist(LIBRARY_FILE, &sequences) {
+		warn!("Failed to persist sequence library: {error}");
+	}
+}
+
+/// Adds a sequence to the library, replacing any with the same name.
+pub fn store(shared: &SharedState, sequence: Sequence) {
+	let mut library = shared.library.lock().unwrap();
+	library.insert(sequence.name.clone(), sequence);
+	persist(&library);
+}
+
+/// Removes a sequence from the library, returning whether it was there.
+pub fn remove(shared: &SharedState, name: &str) -> bool {
+	let mut library = shared.library.lock().unwrap();
+	let removed = library.remove(name).is_some();
+
+	if removed {
+		persist(&library);
+	}
+
+	removed
+}
+
+/// The names of every sequence in the library, in order.
+pub fn names(shared: &SharedState) -> Vec<String> {
+	let mut names: Vec<String> = shared.library.lock().unwrap().keys().cloned().collect();
+	names.sort();
+	names
+}
+
+/// Gets a sequence from the library by name.
+pub fn get(shared: &SharedState, name: &str) -> Option<Sequence> {
+	shared.library.lock().unwrap().get(name).cloned()
+}
+
+/// Validates every sequence in the library against the current mappings, which the library
+/// may predate, reporting any issues to the operator.
+pub fn validate(shared: &SharedState) {
+	let mut sequences: Vec<Sequence> = shared.library.lock().unwrap().values().cloned().collect();
+	sequences.sort_by(|a, b| a.name.cmp(&b.name));
+
+	for sequence in sequences {
+		if !validation::check(shared, &sequence) {
+			warn!("Library sequence '{}' no longer passes validation against the current mappings.", sequence.name);
+		}
+	}
+}
+
+/// Starts a sequence from the library on its own thread with the given arguments.
+///
+/// Fails if it isn't in the library, or if it is already running and `replace` isn't set.
+pub fn start(shared: &SharedState, name: &str, arguments: Arguments, replace: bool) -> Result<(), String> {
+	let sequence = get(shared, name).ok_or_else(|| format!("sequence '{name}' is not in the library"))?;
+	lifecycle::spawn(shared, sequence, Some(arguments), replace)
+}
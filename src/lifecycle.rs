@@ -0,0 +1,317 @@
+use crate::{events::{self, EventKind, Severity}, handler, library::Arguments, operator::{self, FlightReport}, recorder::{self, Entry}, state::SharedState, SEQUENCE_STOP_BOUND};
+use common::{comm::Sequence, sequence::AbortError};
+use jeflog::warn;
+use pyo3::Python;
+use serde::{Deserialize, Serialize};
+use std::{cell::Cell, sync::{atomic::{AtomicU64, Ordering}, mpsc}, thread, time::Duration};
+
+/// Identifier given to the next sequence run.
+static NEXT_RUN_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Where a sequence is in its lifecycle.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum SequenceStatus {
+	/// Received, but its thread has not started running it yet.
+	Queued,
+
+	Running,
+
+	/// Ran to the end of its script.
+	Completed,
+
+	/// Raised an exception.
+	Failed,
+
+	/// Stopped by the operator, or replaced by another run of the same name.
+	Stopped,
+
+	/// Stopped because the vehicle aborted.
+	Aborted,
+}
+
+impl SequenceStatus {
+	/// Whether the sequence has finished, in any way.
+	pub fn is_finished(self) -> bool {
+		!matches!(self, Self::Queued | Self::Running)
+	}
+}
+
+/// The lifecycle of a single run of a sequence.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SequenceRun {
+	/// Distinguishes runs of sequences with the same name.
+	pub id: u64,
+
+	pub name: String,
+	pub status: SequenceStatus,
+
+	/// Mission-elapsed time at which the sequence was queued.
+	pub queued: Duration,
+
+	/// Mission-elapsed time at which the sequence started running.
+	pub started: Option<Duration>,
+
+	/// Mission-elapsed time at which the sequence finished.
+	pub ended: Option<Duration>,
+
+	/// The exception and traceback if the sequence failed.
+	pub traceback: Option<String>,
+
+	/// How long the sequence took to exit after it was stopped or aborted.
+	pub stop_latency: Option<Duration>,
+
+	/// Why and when the sequence was interrupted, if it was. The reason becomes its status
+	/// once its thread actually exits.
+	#[serde(skip)]
+	interruption: Option<(SequenceStatus, Duration)>,
+
+	/// Identifier of the Python thread running the sequence, once it has started.
+	#[serde(skip)]
+	python_thread: Option<u64>,
+}
+
+impl SequenceRun {
+	fn new(name: &str, queued: Duration) -> Self {
+		SequenceRun {
+			id: NEXT_RUN_ID.fetch_add(1, Ordering::Relaxed),
+			name: name.to_owned(),
+			status: SequenceStatus::Queued,
+			queued,
+			started: None,
+			ended: None,
+			traceback: None,
+			stop_latency: None,
+			interruption: None,
+			python_thread: None,
+		}
+	}
+}
+
+thread_local! {
+	/// Whether the current thread is running a tracked sequence.
+	static IN_SEQUENCE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Spawns a thread which runs a sequence, registering it as running under its name. Library
+/// sequences are given their arguments.
+///
+/// Fails while the abort latch is set, or if a sequence with the same name is already
+/// running, unless `replace` is set, in which case the running sequence is stopped first.
+pub fn spawn(shared: &SharedState, sequence: Sequence, arguments: Option<Arguments>, replace: bool) -> Result<(), String> {
+	// the registry stays locked until the new thread is registered, so that it can't finish
+	// and deregister itself before then, and so that no other run of the same name can start
+	let mut sequences = shared.sequences.lock().unwrap();
+	let mut replaced = None;
+
+	if *shared.abort_latched.lock().unwrap() {
+		return Err(format!("sequence '{}' can't run until the vehicle is re-armed after its abort", sequence.name));
+	}
+
+	if sequences.contains_left(&sequence.name) {
+		if !replace {
+			return Err(format!("sequence '{}' is already running", sequence.name));
+		}
+
+		sequences.remove_by_left(&sequence.name);
+		replaced = interrupt(shared, &sequence.name, SequenceStatus::Stopped);
+	}
+
+	let queued = queue(shared, &sequence.name);
+	let id = queued.id;
+	let name = sequence.name.clone();
+	let thread_shared = shared.clone();
+
+	// the thread waits until the queued run is reported so that its transitions arrive in order
+	let (start_tx, start_rx) = mpsc::channel::<()>();
+
+	let handle = thread::spawn(move || {
+		let _ = start_rx.recv();
+		run_queued(&thread_shared, sequence, arguments, id);
+	});
+
+	sequences.insert(name, handle.thread().id());
+	drop(sequences);
+
+	// reporting blocks on the server link, so it mustn't hold up anything waiting on the registry
+	publish(shared, queued);
+	let _ = start_tx.send(());
+
+	halt(shared, replaced);
+	Ok(())
+}
+
+/// Runs a sequence on the current thread, which must already be registered under its name,
+/// tracking and reporting its lifecycle.
+pub fn run(shared: &SharedState, sequence: Sequence) {
+	let queued = queue(shared, &sequence.name);
+	let id = queued.id;
+	publish(shared, queued);
+	run_queued(shared, sequence, None, id);
+
+	// the thread outlives the sequence, so it mustn't be interrupted by a stop which arrived too late
+	Python::with_gil(|py| {
+		if let Ok(python_thread) = handler::python_thread_id(py) {
+			handler::cancel_in_thread(py, python_thread);
+		}
+	});
+}
+
+/// Records that a sequence is about to be run, returning the new run, which is left for
+/// the caller to publish.
+fn queue(shared: &SharedState, name: &str) -> SequenceRun {
+	let run = SequenceRun::new(name, shared.started.elapsed());
+	shared.sequence_runs.lock().unwrap().insert(run.id, run.clone());
+	run
+}
+
+/// Runs a queued sequence on the current thread, deregistering it once it finishes.
+fn run_queued(shared: &SharedState, sequence: Sequence, arguments: Option<Arguments>, id: u64) {
+	let python_thread = Python::with_gil(|py| handler::python_thread_id(py).ok());
+
+	let run = update(shared, id, |run, now| {
+		run.status = SequenceStatus::Running;
+		run.started = Some(now);
+		run.python_thread = python_thread;
+	});
+
+	// a sequence stopped before its thread started never runs
+	let result = match run.interruption {
+		Some(_) => Err(None),
+		None => {
+			let outer = IN_SEQUENCE.with(|in_sequence| in_sequence.replace(true));
+			let result = handler::run_sequence(shared, &sequence, arguments.as_ref());
+			IN_SEQUENCE.with(|in_sequence| in_sequence.set(outer));
+			result
+		},
+	};
+
+	// deregister by thread rather than name, since a replacement may now hold the name
+	shared.sequences
+		.lock()
+		.unwrap()
+		.remove_by_right(&thread::current().id());
+
+	let run = update(shared, id, |run, now| {
+		run.ended = Some(now);
+		run.stop_latency = run.interruption.map(|(_, interrupted)| now.saturating_sub(interrupted));
+
+		let interruption = run.interruption.map(|(reason, _)| reason);
+
+		run.status = match result {
+			Ok(()) => interruption.unwrap_or(SequenceStatus::Completed),
+			Err(Some(traceback)) => {
+				run.traceback = Some(traceback);
+				SequenceStatus::Failed
+			},
+			// an AbortError is only raised once the sequence was stopped or aborted
+			Err(None) => interruption.unwrap_or(SequenceStatus::Aborted),
+		};
+	});
+
+	if let Some(latency) = run.stop_latency.filter(|latency| *latency > SEQUENCE_STOP_BOUND) {
+		warn!("Sequence '{}' took {latency:?} to stop, longer than the expected {SEQUENCE_STOP_BOUND:?}.", run.name);
+
+		events::emit(shared, Severity::Warning, module_path!(), EventKind::SlowStop {
+			name: run.name.clone(),
+			latency,
+		});
+	}
+}
+
+/// Marks the running sequence with the given name as interrupted for the given reason,
+/// which is reported once its thread exits.
+///
+/// Returns the run which was interrupted, which should be passed to `halt` once every lock
+/// is released.
+pub fn interrupt(shared: &SharedState, name: &str, reason: SequenceStatus) -> Option<Interrupted> {
+	let now = shared.started.elapsed();
+	let mut sequence_runs = shared.sequence_runs.lock().unwrap();
+
+	let run = sequence_runs
+		.values_mut()
+		.find(|run| run.name == name && run.interruption.is_none())?;
+
+	run.interruption = Some((reason, now));
+
+	Some(Interrupted { id: run.id, python_thread: run.python_thread? })
+}
+
+/// A run of a sequence which was interrupted while its Python thread was running it.
+#[derive(Clone, Copy, Debug)]
+pub struct Interrupted {
+	id: u64,
+	python_thread: u64,
+}
+
+/// Raises `AbortError` in the Python threads of interrupted sequences so that they stop
+/// promptly, even if they never call into the flight computer again.
+///
+/// The current thread is skipped, as it is either already unwinding or about to run the
+/// abort sequence. The GIL is acquired, so no lock which a sequence might wait on while
+/// holding the GIL may be held by the caller.
+pub fn halt(shared: &SharedState, interrupted: impl IntoIterator<Item = Interrupted>) {
+	Python::with_gil(|py| {
+		let current = handler::python_thread_id(py).ok();
+
+		// a run is forgotten before its thread exits, so while it's still registered its
+		// identifier can't have been reused by another thread
+		let sequence_runs = shared.sequence_runs.lock().unwrap();
+
+		for Interrupted { id, python_thread } in interrupted {
+			if Some(python_thread) == current || !sequence_runs.contains_key(&id) {
+				continue;
+			}
+
+			let _ = handler::raise_in_thread(py, python_thread, py.get_type::<AbortError>());
+		}
+	});
+}
+
+/// Whether the current thread is running a tracked sequence, rather than a trigger.
+pub fn in_sequence() -> bool {
+	IN_SEQUENCE.with(Cell::get)
+}
+
+/// Every run of a sequence which is currently running and has not been stopped.
+pub fn running(shared: &SharedState) -> Vec<SequenceRun> {
+	let mut running: Vec<SequenceRun> = shared.sequence_runs
+		.lock()
+		.unwrap()
+		.values()
+		.filter(|run| run.interruption.is_none())
+		.cloned()
+		.collect();
+
+	running.sort_by_key(|run| run.id);
+	running
+}
+
+/// Applies a transition to a run of a sequence, then reports and returns it. Runs are
+/// forgotten once they finish.
+fn update(shared: &SharedState, id: u64, transition: impl FnOnce(&mut SequenceRun, Duration)) -> SequenceRun {
+	let now = shared.started.elapsed();
+	let mut sequence_runs = shared.sequence_runs.lock().unwrap();
+
+	let run = sequence_runs
+		.get_mut(&id)
+		.expect("sequence runs are only forgotten by their own thread");
+
+	transition(run, now);
+
+	let run = if run.status.is_finished() {
+		sequence_runs.remove(&id).unwrap()
+	} else {
+		run.clone()
+	};
+
+	drop(sequence_runs);
+	publish(shared, run.clone());
+
+	run
+}
+
+/// Records a lifecycle transition and reports it to the server.
+fn publish(shared: &SharedState, run: SequenceRun) {
+	recorder::record(shared, Entry::SequenceRun(run.clone()));
+	operator::report(shared, &FlightReport::SequenceRun(run));
+}
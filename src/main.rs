@@ -1,15 +1,45 @@
+mod builtins;
+mod calibration;
+mod clock;
+mod countdown;
+mod dry_run;
+mod events;
 mod forwarder;
 mod handler;
+mod history;
+mod inventory;
+mod library;
+mod lifecycle;
+mod metrics;
+mod operator;
+mod packet;
+mod persistence;
+mod receiver;
+mod recorder;
+mod replay;
+mod staleness;
 mod state;
 mod switchboard;
+mod units;
+mod validation;
 
-use std::{sync::mpsc::{Receiver, Sender}, time::Duration};
+use std::{env, sync::mpsc::{Receiver, Sender}, time::Duration};
 
 use common::comm::{BoardId, SamControlMessage};
-use jeflog::pass;
+use jeflog::{fail, pass};
+use events::{EventKind, Severity};
 use state::ProgramState;
 
+/// Command line usage when running as the flight computer
+const USAGE: &str = "usage: flight [--metrics <port>] [--data <directory>]";
+
 const SERVO_PORT: u16 = 5025;
+/// Default port on the server which vehicle state updates are forwarded to
+const TELEMETRY_PORT: u16 = 7201;
+/// Default time between vehicle state updates
+const FORWARDING_PERIOD: Duration = Duration::from_millis(10);
+/// Default time between full vehicle state keyframes
+const KEYFRAME_PERIOD: Duration = Duration::from_secs(1);
 /// Where data should be sent 
 const SWITCHBOARD_ADDRESS: (&str, u16) = ("0.0.0.0", 4573);
 /// SAM port to send DataMessage::Identity and DataMessage:Heartbeat to
@@ -19,26 +49,79 @@ const SAM_PORT: u16 = 8378;
 const HEARTBEAT_PERIOD: Duration = Duration::from_millis(150);
 /// Milliseconds of inactivity before a board is declared dead
 const TIME_TIL_DEATH: Duration = Duration::from_millis(100);
+/// How long a sensor reading may go without an update before it is marked stale, unless overridden by its mapping options
+const DEFAULT_STALE_AFTER: Duration = Duration::from_millis(500);
+/// How long a valve may disagree with its commanded state before an alarm is raised, unless overridden by its mapping options
+const DEFAULT_VALVE_SETTLE_TIME: Duration = Duration::from_millis(500);
 
 /// How large the buffer to send a command to a board should be (Can probably replace this with a sizeof(SamControlMessage)).
 const COMMAND_MESSAGE_BUFFER_SIZE: usize = 1_024;
 /// How large the buffer to recieve data from a board should be (Can probably replace this with a sizeof(DataMessage)).
 const DATA_MESSAGE_BUFFER_SIZE: usize = 1_000_000;
+/// How large the buffer to receive a telemetry packet into should be (the largest possible UDP payload).
+const TELEMETRY_BUFFER_SIZE: usize = 65_507;
+/// Largest telemetry packet sent, which fits in an Ethernet frame without IP fragmentation.
+const TELEMETRY_PACKET_SIZE: usize = 1_472;
 /// How large the buffer to send a heartbeat to a board should be (Can probably replace this with a sizeof(SamControlMessage::Heartbeat)).
 const HEARTBEAT_BUFFER_SIZE: usize = 1_024;
 
+/// How many samples of each sensor are kept in its history
+const SENSOR_HISTORY_LENGTH: usize = 1_000;
+
+/// Where calibrated offsets are persisted across restarts, within the data directory
+const CALIBRATION_FILE: &str = "calibrations.postcard";
+/// Where additional telemetry destinations are persisted across restarts, within the data directory
+const DESTINATIONS_FILE: &str = "destinations.postcard";
+/// Where the onboard sequence library is persisted across restarts, within the data directory
+const LIBRARY_FILE: &str = "library.postcard";
+
+/// Directory which recordings are written to, within the data directory
+const RECORDING_DIRECTORY: &str = "recordings";
+/// Size in bytes at which a recording file is rotated
+const RECORDING_FILE_SIZE: u64 = 64 * 1024 * 1024;
+/// Size in bytes which all recording files together may not exceed, after which the oldest are deleted
+const RECORDING_TOTAL_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+/// How often the recording is flushed to disk
+const RECORDING_FLUSH_PERIOD: Duration = Duration::from_secs(1);
+/// Longest to wait for the recording to be flushed to disk on an abort or shutdown before continuing without it
+const RECORDING_FLUSH_TIMEOUT: Duration = Duration::from_millis(100);
+/// How many records may wait to be written before further records are dropped, so a stalled disk can't exhaust memory
+const RECORDING_QUEUE_LENGTH: usize = 10_000;
+/// Slowest factor a recording may be replayed at, below which pacing could overflow
+const MIN_REPLAY_SPEED: f64 = 0.001;
+
 /// How many boards should be refreshed before checking for timeout
 const REFRESH_COUNT: u8 = 5;
 
 
+/// How long the metrics endpoint waits on a slow scraper before giving up on its request
+const METRICS_REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many undelivered events are kept while the server is not connected
+const EVENT_BACKLOG_LENGTH: usize = 1_000;
+/// How often delivery of undelivered events is retried
+const EVENT_RETRY_PERIOD: Duration = Duration::from_secs(1);
+/// Longest a stopped or aborted sequence should take to exit before a warning is emitted
+const SEQUENCE_STOP_BOUND: Duration = Duration::from_millis(100);
+/// How often a sleeping sequence checks whether it was stopped
+const SLEEP_POLL_PERIOD: Duration = Duration::from_millis(10);
+/// How often the trigger thread checks whether the abort latch was cleared while triggers are suspended
+const LATCHED_TRIGGER_POLL_PERIOD: Duration = Duration::from_millis(10);
+/// Most actions a dry run of a sequence may take before it is cut off
+const MAX_DRY_RUN_ACTIONS: usize = 10_000;
+/// How often the countdown checks for scheduled actions which have come due
+const COUNTDOWN_TICK_PERIOD: Duration = Duration::from_millis(5);
 /// Board ID of the flight computer
 const FC_BOARD_ID: &str = "flight-01";
 
 type CommandSender = Sender<(BoardId, SamControlMessage)>;
 
+// kept for the terminal interface, which is currently disabled
+#[allow(dead_code)]
 type TuiReceiver = Receiver<TuiMessage>;
+#[allow(dead_code)]
 type TuiSender = Sender<TuiMessage>;
 
+#[allow(dead_code)]
 enum TuiMessage {
 	/// When a new board is found
 	Identity(BoardId),
@@ -52,10 +135,71 @@ enum TuiMessage {
 
 
 fn main() {
+	let mut args = env::args().skip(1).peekable();
+	let mut metrics_port = None;
+
+	match args.peek().map(String::as_str) {
+		// replay mode runs a recording through the worker instead of connecting to boards
+		Some("replay") => {
+			match replay::ReplayOptions::parse(args.skip(1)) {
+				Ok(options) => replay::replay(options),
+				Err(error) => fail!("{error}"),
+			}
+
+			return;
+		},
+		// receive mode listens for telemetry as the server would and reports packet loss
+		Some("receive") => {
+			match receiver::ReceiveOptions::parse(args.skip(1)) {
+				Ok(options) => receiver::receive(options),
+				Err(error) => fail!("{error}"),
+			}
+
+			return;
+		},
+		_ => {},
+	}
+
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			// serves OpenMetrics gauges for a monitoring stack to scrape once the flight state exists
+			"--metrics" => match args.next().and_then(|port| port.parse::<u16>().ok()) {
+				Some(port) => metrics_port = Some(port),
+				None => {
+					fail!("{USAGE}");
+					return;
+				}
+			},
+			// persisted files and recordings are kept here rather than next to the executable
+			"--data" => match args.next() {
+				Some(directory) => persistence::set_data_directory(directory.into()),
+				None => {
+					fail!("{USAGE}");
+					return;
+				}
+			},
+			_ => {
+				fail!("unexpected argument '{arg}'\n{USAGE}");
+				return;
+			},
+		}
+	}
+
 	let mut state = ProgramState::Init;
 
 	loop {
 		pass!("Transitioned to state: {state}");
+
+		if let Some(shared) = state.shared() {
+			recorder::record(shared, recorder::Entry::Transition(state.to_string()));
+			events::emit(shared, Severity::Info, module_path!(), EventKind::Transition(state.to_string()));
+			*shared.program_state.lock().unwrap() = state.name();
+
+			if let Some(port) = metrics_port.take() {
+				metrics::start(shared, port);
+			}
+		}
+
 		state = state.next();
 	}
 }
@@ -0,0 +1,163 @@
+use crate::{staleness, state::{ProgramState, SharedState}, units::UnitOfMeasure, METRICS_REQUEST_TIMEOUT};
+use common::comm::ValveState;
+use jeflog::{fail, pass, warn};
+use std::{collections::BTreeSet, fmt::Write as _, io::{Read, Write}, net::{TcpListener, TcpStream}, sync::TryLockError};
+
+/// Every valve state, in the order they are listed in each valve's state set.
+pub const VALVE_STATES: [ValveState; 5] = [
+	ValveState::Undetermined,
+	ValveState::Disconnected,
+	ValveState::Open,
+	ValveState::Closed,
+	ValveState::Fault,
+];
+
+/// Starts serving OpenMetrics gauges over HTTP on the given port for a monitoring stack
+/// to scrape.
+pub fn start(shared: &SharedState, port: u16) {
+	let listener = match TcpListener::bind(("0.0.0.0", port)) {
+		Ok(listener) => listener,
+		Err(error) => {
+			fail!("Failed to bind metrics endpoint to port {port}: {error}");
+			return;
+		}
+	};
+
+	pass!("Serving metrics on port \x1b[1m{port}\x1b[0m.");
+	shared.spawn("metrics", serve(shared.clone(), listener));
+}
+
+/// Answers scrapes one at a time for as long as the listener is open.
+fn serve(shared: SharedState, listener: TcpListener) -> impl FnOnce() {
+	move || {
+		for stream in listener.incoming() {
+			match stream {
+				Ok(stream) => {
+					if let Err(error) = respond(&shared, stream) {
+						warn!("Failed to respond to metrics request: {error}");
+					}
+				},
+				Err(error) => warn!("Failed to accept metrics connection: {error}"),
+			}
+		}
+	}
+}
+
+/// Answers a single HTTP request with the current metrics, whatever the path requested.
+fn respond(shared: &SharedState, mut stream: TcpStream) -> std::io::Result<()> {
+	stream.set_read_timeout(Some(METRICS_REQUEST_TIMEOUT))?;
+	stream.set_write_timeout(Some(METRICS_REQUEST_TIMEOUT))?;
+
+	// the request itself is irrelevant, but it must be read before the response is sent
+	let mut request = [0; 1_024];
+	let _ = stream.read(&mut request)?;
+
+	let body = render(shared);
+
+	write!(
+		stream,
+		"HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+		body.len(),
+	)
+}
+
+/// Renders every metric in the OpenMetrics text format.
+fn render(shared: &SharedState) -> String {
+	let mut out = String::new();
+
+	let vehicle_state = shared.vehicle_state.lock().unwrap().clone();
+
+	out.push_str("# TYPE flight_sensor_reading gauge\n");
+
+	for (name, measurement) in &vehicle_state.sensor_readings {
+		let unit = UnitOfMeasure::from(measurement.unit);
+		let _ = writeln!(out, "flight_sensor_reading{{sensor=\"{}\",unit=\"{}\"}} {}", escape(name), escape(&unit.to_string()), measurement.value);
+	}
+
+	let stale: BTreeSet<String> = staleness::stale_readings(&shared.reading_timestamps.lock().unwrap(), shared.clock.now())
+		.cloned()
+		.collect();
+
+	out.push_str("# TYPE flight_sensor_stale gauge\n");
+
+	for name in vehicle_state.sensor_readings.keys() {
+		let _ = writeln!(out, "flight_sensor_stale{{sensor=\"{}\"}} {}", escape(name), stale.contains(name) as u8);
+	}
+
+	out.push_str("# TYPE flight_valve_actual stateset\n");
+
+	for (name, state) in &vehicle_state.valve_states {
+		for candidate in VALVE_STATES {
+			let _ = writeln!(out, "flight_valve_actual{{valve=\"{}\",flight_valve_actual=\"{candidate:?}\"}} {}", escape(name), (state.actual == candidate) as u8);
+		}
+	}
+
+	out.push_str("# TYPE flight_valve_commanded stateset\n");
+
+	for (name, state) in &vehicle_state.valve_states {
+		for candidate in VALVE_STATES {
+			let _ = writeln!(out, "flight_valve_commanded{{valve=\"{}\",flight_valve_commanded=\"{candidate:?}\"}} {}", escape(name), (state.commanded == candidate) as u8);
+		}
+	}
+
+	// boards are known once they have connected or sent data, and stay listed after losing comms
+	let connected = shared.connected_boards.lock().unwrap().clone();
+	let mut boards: BTreeSet<String> = shared.channel_inventory.lock().unwrap().boards().cloned().collect();
+	boards.extend(connected.iter().cloned());
+
+	out.push_str("# TYPE flight_board_connected gauge\n");
+
+	for board_id in &boards {
+		let _ = writeln!(out, "flight_board_connected{{board=\"{}\"}} {}", escape(board_id), connected.contains(board_id) as u8);
+	}
+
+	out.push_str("# TYPE flight_sequence_running gauge\n");
+
+	for (name, _) in shared.sequences.lock().unwrap().iter() {
+		let _ = writeln!(out, "flight_sequence_running{{sequence=\"{}\"}} 1", escape(name));
+	}
+
+	// the trigger thread holds its lock while a tripped trigger's sequence runs, so don't wait for it
+	match shared.triggers.try_lock() {
+		Ok(triggers) => {
+			out.push_str("# TYPE flight_trigger_active gauge\n");
+
+			for trigger in triggers.iter() {
+				let _ = writeln!(out, "flight_trigger_active{{trigger=\"{}\"}} {}", escape(&trigger.name), trigger.active as u8);
+			}
+		},
+		Err(TryLockError::WouldBlock) => {},
+		Err(TryLockError::Poisoned(_)) => fail!("Trigger lock is poisoned."),
+	}
+
+	out.push_str("# TYPE flight_thread_alive gauge\n");
+
+	for (name, handle) in shared.threads.lock().unwrap().iter() {
+		let _ = writeln!(out, "flight_thread_alive{{thread=\"{}\"}} {}", escape(name), !handle.is_finished() as u8);
+	}
+
+	let program_state = *shared.program_state.lock().unwrap();
+
+	out.push_str("# TYPE flight_program_state stateset\n");
+
+	for candidate in ProgramState::NAMES {
+		let _ = writeln!(out, "flight_program_state{{flight_program_state=\"{candidate}\"}} {}", (program_state == candidate) as u8);
+	}
+
+	out.push_str("# TYPE flight_abort_latched gauge\n");
+	let _ = writeln!(out, "flight_abort_latched {}", *shared.abort_latched.lock().unwrap() as u8);
+
+	out.push_str("# TYPE flight_uptime_seconds gauge\n");
+	let _ = writeln!(out, "flight_uptime_seconds {}", shared.started.elapsed().as_secs_f64());
+
+	out.push_str("# EOF\n");
+	out
+}
+
+/// Escapes a label value as required by the OpenMetrics text format.
+fn escape(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
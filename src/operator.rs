@@ -0,0 +1,454 @@
+use crate::{calibration::{self, Calibration}, countdown::{self, CountdownCommand}, dry_run::{self, DryRunReport}, events::Event, handler, forwarder::{self, ForwarderConfig, TelemetryDestination}, inventory::ChannelSummary, library::{self, Arguments}, lifecycle::{self, SequenceRun}, recorder::{self, Entry}, state::{ProgramState, SharedState}, units::UnitSystem, validation::{self, ValidationIssue}};
+use common::comm::Sequence;
+use jeflog::{fail, pass, warn};
+use serde::{Deserialize, Serialize};
+use std::{io::Write, net::TcpStream, time::Duration};
+
+/// First byte of an operator message which carries an `OperatorCommand` rather than a
+/// `FlightControlMessage`.
+///
+/// Postcard encodes enum variants as varints, so a leading byte with the continuation bit
+/// set can never begin one of the handful of `FlightControlMessage` variants.
+pub const EXTENDED_COMMAND_TAG: u8 = 0xFE;
+
+/// Commands from the operator which extend those available in `FlightControlMessage`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum OperatorCommand {
+	/// Replaces the flight-side options of every mapping.
+	MappingOptions(Vec<MappingOptions>),
+
+	/// Replaces every red-line limit monitored by the worker.
+	Limits(Vec<Limit>),
+
+	/// Zeroes a sensor by averaging its readings over `window` while it is at `expected`.
+	Calibrate {
+		text_id: String,
+		window: Duration,
+		expected: f64,
+	},
+
+	/// Changes how often and where vehicle state updates are forwarded.
+	ConfigureForwarder(ForwarderConfig),
+
+	/// Adds an additional telemetry destination, replacing any with the same name.
+	AddDestination(TelemetryDestination),
+
+	/// Removes the additional telemetry destination with the given name.
+	RemoveDestination(String),
+
+	/// Enables or disables recording and forwarding data points which match no mapping.
+	RawPassthrough(bool),
+
+	/// Requests a `FlightReport::Inventory` of every channel reported by each board.
+	RequestInventory,
+
+	/// Runs a sequence, stopping any running sequence with the same name if `replace` is set
+	/// rather than rejecting the new one.
+	RunSequence {
+		sequence: Sequence,
+		replace: bool,
+	},
+
+	/// Requests a `FlightReport::RunningSequences` listing every sequence currently running.
+	RequestRunningSequences,
+
+	/// Checks a sequence against the current mappings without running it, answered with a
+	/// `FlightReport::Validation`.
+	ValidateSequence(Sequence),
+
+	/// Runs a sequence against a simulated copy of the vehicle state without actuating
+	/// anything, answered with a `FlightReport::DryRun`.
+	DryRun(Sequence),
+
+	/// Adds a sequence to the onboard library, replacing any with the same name.
+	StoreSequence(Sequence),
+
+	/// Removes the sequence with the given name from the onboard library.
+	DeleteSequence(String),
+
+	/// Requests a `FlightReport::Library` listing every sequence in the onboard library.
+	RequestLibrary,
+
+	/// Starts a sequence from the onboard library by name, stopping any running sequence
+	/// with the same name if `replace` is set rather than rejecting it.
+	StartSequence {
+		name: String,
+		arguments: Arguments,
+		replace: bool,
+	},
+
+	/// Controls the countdown clock or schedules actions relative to T-0.
+	Countdown(CountdownCommand),
+
+	/// Acknowledges an abort, clearing the abort latch so that sequences and triggers may
+	/// run again.
+	Rearm,
+
+	/// Discards the offset calibrated onboard for the given mapping, or for every mapping if
+	/// not given, restoring the offset sent by the server.
+	ClearCalibration(Option<String>),
+}
+
+/// Reports sent from the flight computer to the server over the operator link.
+///
+/// Each report is COBS-framed so that it is delimited by a zero byte on the stream.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum FlightReport {
+	/// A sensor was successfully calibrated.
+	Calibrated(Calibration),
+
+	/// A sensor could not be calibrated.
+	CalibrationFailed {
+		text_id: String,
+		reason: String,
+	},
+
+	/// Every channel reported by each board, including those matching no mapping.
+	Inventory(Vec<ChannelSummary>),
+
+	/// Something noteworthy happened on the flight computer.
+	Event(Event),
+
+	/// A sequence transitioned in its lifecycle.
+	///
+	/// Sequences started by the server, a countdown or `start_sequence` are tracked, as is
+	/// the abort sequence. Trigger scripts run inline on the trigger thread and are only
+	/// reported through `EventKind::TriggerFired` and `EventKind::TriggerFailed`.
+	SequenceRun(SequenceRun),
+
+	/// The latest run of every sequence currently running, excluding triggers.
+	RunningSequences(Vec<SequenceRun>),
+
+	/// Problems found in a sequence before running it.
+	Validation {
+		name: String,
+		issues: Vec<ValidationIssue>,
+	},
+
+	/// Every action a sequence would have taken, had it not been a dry run.
+	DryRun(DryRunReport),
+
+	/// The name of every sequence in the onboard library.
+	Library(Vec<String>),
+
+	/// The offsets calibrated onboard for these mappings were discarded.
+	CalibrationsCleared(Vec<String>),
+
+	/// A countdown command was refused for the given reason.
+	CountdownRejected(String),
+}
+
+/// Flight-side options for a single `NodeMapping`, matched by its text ID.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MappingOptions {
+	/// The text ID of the mapping which these options apply to.
+	pub text_id: String,
+
+	/// How long a reading may go without being updated before it is considered stale.
+	/// Defaults to `DEFAULT_STALE_AFTER` if not set.
+	pub stale_after: Option<Duration>,
+
+	/// Thresholds used to estimate the actual state of a valve. Defaults to
+	/// `ValveThresholds::default()` if not set.
+	pub valve_thresholds: Option<ValveThresholds>,
+
+	/// How long a valve's actual state may disagree with its commanded state before an
+	/// alarm is raised. Defaults to `DEFAULT_VALVE_SETTLE_TIME` if not set.
+	pub valve_settle_time: Option<Duration>,
+
+	/// Whether the vehicle should abort when a valve mismatch alarm is raised.
+	pub abort_on_valve_mismatch: bool,
+
+	/// The system of units this reading is converted to in forwarded telemetry, in addition
+	/// to its raw value. Not converted if not set.
+	pub telemetry_units: Option<UnitSystem>,
+
+	/// The minimum time between changes to this reading being sent to the server in
+	/// telemetry deltas. Sent on every change if not set.
+	pub downlink_period: Option<Duration>,
+}
+
+/// Voltage thresholds and hysteresis used to estimate the actual state of a valve.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ValveThresholds {
+	/// Voltage below which an unpowered valve is considered closed rather than disconnected.
+	pub closed_voltage: f64,
+
+	/// Voltage at or above which a powered valve is considered open rather than faulted.
+	pub open_voltage: f64,
+
+	/// How far past a voltage threshold a reading must go before the estimate changes.
+	pub voltage_hysteresis: f64,
+
+	/// How far past the powered current threshold a reading must go before the estimate changes.
+	pub current_hysteresis: f64,
+}
+
+impl Default for ValveThresholds {
+	fn default() -> Self {
+		ValveThresholds {
+			closed_voltage: 4.0,
+			open_voltage: 20.0,
+			voltage_hysteresis: 0.0,
+			current_hysteresis: 0.0,
+		}
+	}
+}
+
+/// A red-line limit on a single sensor reading, evaluated natively as data arrives.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Limit {
+	/// The name of the sensor reading which this limit applies to.
+	pub text_id: String,
+
+	/// How severe a violation of this limit is.
+	pub severity: LimitSeverity,
+
+	/// The value below which the reading violates this limit, if any.
+	pub low: Option<f64>,
+
+	/// The value above which the reading violates this limit, if any.
+	pub high: Option<f64>,
+
+	/// How long the reading must continuously violate this limit before it trips.
+	pub persistence: Duration,
+
+	/// What to do once this limit trips.
+	pub action: LimitAction,
+}
+
+/// The severity of a red-line limit.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum LimitSeverity {
+	Caution,
+	Warning,
+}
+
+/// The response taken when a red-line limit trips.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum LimitAction {
+	/// Only raise an alarm.
+	Alarm,
+
+	/// Raise an alarm and run the given contingency sequence.
+	RunSequence(Sequence),
+
+	/// Raise an alarm and abort immediately.
+	Abort,
+}
+
+/// Deserializes and executes an extended operator command, returning the next state.
+pub fn handle_command(message: &[u8], server_socket: TcpStream, shared: SharedState) -> ProgramState {
+	let command = match postcard::from_bytes::<OperatorCommand>(message) {
+		Ok(command) => command,
+		Err(error) => {
+			warn!("Failed to deserialize extended operator command: {}.", error.to_string());
+			return ProgramState::WaitForOperator { server_socket, shared };
+		}
+	};
+
+	match command {
+		OperatorCommand::MappingOptions(options) => {
+			pass!("Received mapping options from server: {options:#?}");
+			recorder::record(&shared, Entry::MappingOptions(options.clone()));
+
+			*shared.mapping_options.lock().unwrap() = options
+				.into_iter()
+				.map(|options| (options.text_id.clone(), options))
+				.collect();
+
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::Limits(limits) => {
+			pass!("Received limits from server: {limits:#?}");
+
+			// contingencies run unattended, so they're checked as strictly as uploaded sequences
+			let invalid = limits
+				.iter()
+				.filter_map(|limit| match &limit.action {
+					LimitAction::RunSequence(sequence) => Some(sequence),
+					_ => None,
+				})
+				.find(|sequence| !validation::check(&shared, sequence));
+
+			if let Some(sequence) = invalid {
+				fail!("Rejected limits because contingency sequence '{}' failed validation.", sequence.name);
+				return ProgramState::WaitForOperator { server_socket, shared };
+			}
+
+			recorder::record(&shared, Entry::Limits(limits.clone()));
+			*shared.limits.lock().unwrap() = limits;
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::Calibrate { text_id, window, expected } => {
+			pass!("Received instruction to calibrate '{text_id}' from server.");
+			calibration::calibrate_in_background(shared.clone(), text_id, window, expected);
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::ConfigureForwarder(config) => {
+			pass!("Received forwarder configuration from server: {config:#?}");
+
+			if config.period.is_zero() {
+				warn!("Ignoring forwarder configuration with a zero period.");
+			} else {
+				*shared.forwarder_config.lock().unwrap() = config;
+			}
+
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::AddDestination(destination) => {
+			pass!("Received telemetry destination from server: {destination:#?}");
+
+			if destination.period.is_zero() {
+				warn!("Ignoring telemetry destination '{}' with a zero period.", destination.name);
+				return ProgramState::WaitForOperator { server_socket, shared };
+			}
+
+			let mut destinations = shared.telemetry_destinations.lock().unwrap();
+			destinations.retain(|existing| existing.name != destination.name);
+			destinations.push(destination);
+			persist_destinations(&destinations);
+			drop(destinations);
+
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::RemoveDestination(name) => {
+			let mut destinations = shared.telemetry_destinations.lock().unwrap();
+			let count = destinations.len();
+			destinations.retain(|existing| existing.name != name);
+
+			if destinations.len() < count {
+				pass!("Removed telemetry destination '{name}'.");
+				persist_destinations(&destinations);
+			} else {
+				warn!("Telemetry destination '{name}' does not exist.");
+			}
+
+			drop(destinations);
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::RawPassthrough(enabled) => {
+			pass!("Raw passthrough of unmapped channels {}.", if enabled { "enabled" } else { "disabled" });
+			*shared.raw_passthrough.lock().unwrap() = enabled;
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::RequestInventory => {
+			let inventory = shared.channel_inventory.lock().unwrap().summarize(shared.clock.now());
+			report(&shared, &FlightReport::Inventory(inventory));
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::RunSequence { sequence, replace } => {
+			pass!("Received sequence '{}' from server.", sequence.name);
+
+			if !validation::check(&shared, &sequence) {
+				fail!("Rejected sequence '{}' because it failed validation.", sequence.name);
+				return ProgramState::WaitForOperator { server_socket, shared };
+			}
+
+			if let Err(error) = lifecycle::spawn(&shared, sequence, None, replace) {
+				warn!("Rejected sequence: {error}.");
+			}
+
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::RequestRunningSequences => {
+			report(&shared, &FlightReport::RunningSequences(lifecycle::running(&shared)));
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::ValidateSequence(sequence) => {
+			let issues = validation::validate(&shared, &sequence.script);
+			report(&shared, &FlightReport::Validation { name: sequence.name, issues });
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::DryRun(sequence) => {
+			pass!("Dry-running sequence '{}'.", sequence.name);
+			dry_run::start(&shared, sequence);
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::StoreSequence(sequence) => {
+			if !validation::check(&shared, &sequence) {
+				fail!("Refused to store sequence '{}' because it failed validation.", sequence.name);
+				return ProgramState::WaitForOperator { server_socket, shared };
+			}
+
+			pass!("Stored sequence '{}' in the library.", sequence.name);
+			library::store(&shared, sequence);
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::DeleteSequence(name) => {
+			if library::remove(&shared, &name) {
+				pass!("Deleted sequence '{name}' from the library.");
+			} else {
+				warn!("Sequence '{name}' is not in the library.");
+			}
+
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::RequestLibrary => {
+			report(&shared, &FlightReport::Library(library::names(&shared)));
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::StartSequence { name, arguments, replace } => {
+			pass!("Starting library sequence '{name}' with arguments {arguments:?}.");
+
+			if let Err(error) = library::start(&shared, &name, arguments, replace) {
+				warn!("Failed to start library sequence: {error}.");
+			}
+
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::Countdown(command) => {
+			countdown::control(&shared, command);
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::Rearm => {
+			match handler::rearm(&shared) {
+				Ok(()) => pass!("Vehicle re-armed by operator."),
+				Err(error) => warn!("Refused to re-arm vehicle: {error}."),
+			}
+
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+		OperatorCommand::ClearCalibration(text_id) => {
+			let cleared = calibration::clear(&shared, text_id.as_deref());
+
+			if cleared.is_empty() {
+				warn!("No calibrated offsets to clear.");
+			} else {
+				pass!("Cleared calibrated offsets of {cleared:?}.");
+			}
+
+			report(&shared, &FlightReport::CalibrationsCleared(cleared));
+			ProgramState::WaitForOperator { server_socket, shared }
+		},
+	}
+}
+
+fn persist_destinations(destinations: &[TelemetryDestination]) {
+	if let Err(error) = forwarder::persist_destinations(destinations) {
+		warn!("Failed to persist telemetry destinations: {error}");
+	}
+}
+
+/// Sends a report to the server over the operator link, if connected.
+pub fn report(shared: &SharedState, report: &FlightReport) {
+	if let Err(error) = send_report(shared, report) {
+		warn!("Dropping report {report:?}: {error}");
+	}
+}
+
+/// Sends a report to the server over the operator link, failing if it is not connected.
+pub fn send_report(shared: &SharedState, report: &FlightReport) -> Result<(), String> {
+	let mut server_link = shared.server_link.lock().unwrap();
+
+	let Some(stream) = server_link.as_mut() else {
+		return Err("the server is not connected".to_owned());
+	};
+
+	let serialized = postcard::to_allocvec_cobs(report)
+		.map_err(|error| format!("failed to serialize report with Postcard: {error}"))?;
+
+	stream
+		.write_all(&serialized)
+		.map_err(|error| format!("failed to send report to server: {error}"))
+}
@@ -0,0 +1,414 @@
+use crate::TELEMETRY_PACKET_SIZE;
+use serde::{Deserialize, Serialize};
+use std::{collections::{BTreeSet, VecDeque}, fmt, time::Duration};
+
+/// Version of the telemetry packet format, incremented whenever the layout changes.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Size of the CRC-32 which trails every packet.
+const CHECKSUM_SIZE: usize = 4;
+
+/// Largest possible postcard encoding of a `PacketHeader`, with every varint at its
+/// maximum length.
+const MAX_HEADER_SIZE: usize = 1 + 5 + (10 + 5) + 5 + 3 + 3;
+
+/// Largest fragment of a frame which fits in a single packet.
+pub const MAX_FRAGMENT_SIZE: usize = TELEMETRY_PACKET_SIZE - MAX_HEADER_SIZE - CHECKSUM_SIZE;
+
+/// How many partially received frames are kept before the oldest is given up on.
+const MAX_PENDING_FRAMES: usize = 8;
+
+/// How many missing sequence numbers are remembered so that late packets can be told apart
+/// from duplicates.
+const MISSING_CAPACITY: usize = 1_024;
+
+/// Header which precedes every fragment of a telemetry frame sent over UDP.
+///
+/// A packet is laid out as the postcard-encoded header, followed by a fragment of the
+/// postcard-encoded frame, followed by a little-endian CRC-32 of everything before it. The
+/// version is the first byte, so receivers may reject incompatible packets before decoding
+/// anything else. Frames which fit in one packet are sent as a single fragment.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct PacketHeader {
+	/// The `PROTOCOL_VERSION` of the sender.
+	pub version: u8,
+
+	/// Increases by one with every packet sent to a destination.
+	pub sequence: u32,
+
+	/// Time since the flight computer started when the frame was captured.
+	pub elapsed: Duration,
+
+	/// Increases by one with every frame sent to a destination, shared by all of its fragments.
+	pub frame: u32,
+
+	/// Index of this fragment within the frame.
+	pub fragment: u16,
+
+	/// Number of fragments the frame was split into.
+	pub fragments: u16,
+}
+
+/// Reasons a received packet could not be decoded.
+#[derive(Debug)]
+pub enum PacketError {
+	/// The packet is too short to contain a header and checksum.
+	Truncated,
+
+	/// The checksum does not match the contents of the packet.
+	Checksum,
+
+	/// The packet was sent with an unsupported protocol version.
+	Version(u8),
+
+	/// The header could not be deserialized.
+	Header(postcard::Error),
+}
+
+impl fmt::Display for PacketError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Truncated => write!(f, "packet is truncated"),
+			Self::Checksum => write!(f, "checksum mismatch"),
+			Self::Version(version) => write!(f, "unsupported protocol version {version} (expected {PROTOCOL_VERSION})"),
+			Self::Header(error) => write!(f, "malformed header: {error}"),
+		}
+	}
+}
+
+/// Writes a header and fragment into `buffer` as a checksummed packet, returning the
+/// portion of the buffer which holds the packet.
+pub fn encode<'b>(header: &PacketHeader, fragment: &[u8], buffer: &'b mut [u8]) -> postcard::Result<&'b mut [u8]> {
+	let header_size = postcard::to_slice(header, buffer)?.len();
+	let size = header_size + fragment.len();
+
+	if buffer.len() < size + CHECKSUM_SIZE {
+		return Err(postcard::Error::SerializeBufferFull);
+	}
+
+	buffer[header_size..size].copy_from_slice(fragment);
+
+	let checksum = crc32(&buffer[..size]);
+	buffer[size..size + CHECKSUM_SIZE].copy_from_slice(&checksum.to_le_bytes());
+
+	Ok(&mut buffer[..size + CHECKSUM_SIZE])
+}
+
+/// Verifies and splits a packet into its header and fragment.
+pub fn decode(packet: &[u8]) -> Result<(PacketHeader, &[u8]), PacketError> {
+	if packet.len() < CHECKSUM_SIZE + 1 {
+		return Err(PacketError::Truncated);
+	}
+
+	let (contents, checksum) = packet.split_at(packet.len() - CHECKSUM_SIZE);
+
+	if crc32(contents) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+		return Err(PacketError::Checksum);
+	}
+
+	if contents[0] != PROTOCOL_VERSION {
+		return Err(PacketError::Version(contents[0]));
+	}
+
+	postcard::take_from_bytes::<PacketHeader>(contents)
+		.map_err(PacketError::Header)
+}
+
+/// Computes the IEEE 802.3 CRC-32 of the given bytes.
+fn crc32(bytes: &[u8]) -> u32 {
+	let mut crc = !0_u32;
+
+	for byte in bytes {
+		crc ^= *byte as u32;
+
+		for _ in 0..8 {
+			crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+		}
+	}
+
+	!crc
+}
+
+/// A frame which has only been partially received.
+struct PartialFrame {
+	frame: u32,
+	fragments: Vec<Option<Vec<u8>>>,
+	remaining: usize,
+}
+
+/// Reassembles frames from their fragments, which may arrive in any order.
+#[derive(Default)]
+pub struct Reassembler {
+	/// Frames which were fully reassembled.
+	pub completed: u64,
+
+	/// Frames which were given up on because a fragment never arrived.
+	pub incomplete: u64,
+
+	/// Partially received frames, oldest first.
+	pending: VecDeque<PartialFrame>,
+
+	/// Buffer which the most recently completed frame is joined into.
+	assembled: Vec<u8>,
+}
+
+impl Reassembler {
+	/// Adds a received fragment, returning the serialized frame once all of its fragments
+	/// have been received.
+	pub fn push(&mut self, header: &PacketHeader, fragment: &[u8]) -> Option<&[u8]> {
+		let fragments = header.fragments as usize;
+		let index = header.fragment as usize;
+
+		if index >= fragments {
+			return None;
+		}
+
+		// most frames fit in one packet and need no bookkeeping
+		if fragments == 1 {
+			self.completed += 1;
+			self.assembled.clear();
+			self.assembled.extend_from_slice(fragment);
+			return Some(&self.assembled);
+		}
+
+		let position = match self.pending.iter().position(|partial| partial.frame == header.frame) {
+			Some(position) => position,
+			None => {
+				if self.pending.len() >= MAX_PENDING_FRAMES {
+					self.pending.pop_front();
+					self.incomplete += 1;
+				}
+
+				self.pending.push_back(PartialFrame {
+					frame: header.frame,
+					fragments: vec![None; fragments],
+					remaining: fragments,
+				});
+
+				self.pending.len() - 1
+			},
+		};
+
+		let partial = &mut self.pending[position];
+
+		// a frame number reused with a different number of fragments means the sender
+		// restarted, so the frame pending under that number can never be completed
+		if partial.fragments.len() != fragments {
+			self.incomplete += 1;
+
+			*partial = PartialFrame {
+				frame: header.frame,
+				fragments: vec![None; fragments],
+				remaining: fragments,
+			};
+		}
+
+		if partial.fragments[index].is_none() {
+			partial.fragments[index] = Some(fragment.to_vec());
+			partial.remaining -= 1;
+		}
+
+		if partial.remaining > 0 {
+			return None;
+		}
+
+		let partial = self.pending.remove(position)?;
+		self.completed += 1;
+		self.assembled.clear();
+
+		for fragment in partial.fragments.into_iter().flatten() {
+			self.assembled.extend_from_slice(&fragment);
+		}
+
+		Some(&self.assembled)
+	}
+}
+
+/// Counts received, lost, reordered and duplicate packets from a single sender by their
+/// sequence numbers.
+#[derive(Debug, Default)]
+pub struct PacketStatistics {
+	/// Packets which were received intact.
+	pub received: u64,
+
+	/// Packets which have not been received after a later packet was.
+	pub lost: u64,
+
+	/// Packets which were received after a later packet.
+	pub reordered: u64,
+
+	/// Packets whose sequence number was already received.
+	pub duplicates: u64,
+
+	/// Packets which failed to decode.
+	pub corrupted: u64,
+
+	/// The sequence number expected next, if any packet has been received.
+	next: Option<u32>,
+
+	/// Sequence numbers which were skipped and may still arrive late.
+	missing: BTreeSet<u32>,
+}
+
+impl PacketStatistics {
+	/// Accounts for a packet with the given header having been received.
+	pub fn receive(&mut self, header: &PacketHeader) {
+		self.received += 1;
+
+		let sequence = header.sequence;
+
+		// the sender restarted, so earlier sequence numbers no longer apply
+		if sequence == 0 {
+			self.next = None;
+			self.missing.clear();
+		}
+
+		let Some(next) = self.next else {
+			self.next = Some(sequence.wrapping_add(1));
+			return;
+		};
+
+		if sequence == next {
+			self.next = Some(next.wrapping_add(1));
+		} else if sequence > next {
+			self.lost += (sequence - next) as u64;
+
+			// only the most recent gap can still be filled by late packets
+			for skipped in next.max(sequence.saturating_sub(MISSING_CAPACITY as u32))..sequence {
+				self.missing.insert(skipped);
+			}
+
+			while self.missing.len() > MISSING_CAPACITY {
+				self.missing.pop_first();
+			}
+
+			self.next = Some(sequence.wrapping_add(1));
+		} else if self.missing.remove(&sequence) {
+			self.lost -= 1;
+			self.reordered += 1;
+		} else {
+			self.duplicates += 1;
+		}
+	}
+
+	/// Accounts for a packet which could not be decoded.
+	pub fn reject(&mut self) {
+		self.corrupted += 1;
+	}
+
+	/// The fraction of packets sent which were lost, between zero and one.
+	pub fn loss_ratio(&self) -> f64 {
+		let sent = self.received - self.duplicates + self.lost;
+
+		if sent == 0 {
+			0.0
+		} else {
+			self.lost as f64 / sent as f64
+		}
+	}
+}
+
+impl fmt::Display for PacketStatistics {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{} received, {} lost ({:.2}%), {} reordered, {} duplicate, {} corrupted",
+			self.received,
+			self.lost,
+			self.loss_ratio() * 100.0,
+			self.reordered,
+			self.duplicates,
+			self.corrupted,
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn header(sequence: u32, frame: u32, fragment: u16, fragments: u16) -> PacketHeader {
+		PacketHeader {
+			version: PROTOCOL_VERSION,
+			sequence,
+			elapsed: Duration::ZERO,
+			frame,
+			fragment,
+			fragments,
+		}
+	}
+
+	#[test]
+	fn crc32_matches_check_value() {
+		assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+	}
+
+	#[test]
+	fn packets_round_trip_and_reject_corruption() {
+		let mut buffer = [0; TELEMETRY_PACKET_SIZE];
+		let packet = encode(&header(7, 3, 0, 1), b"frame", &mut buffer).unwrap().to_vec();
+
+		let (decoded, fragment) = decode(&packet).unwrap();
+		assert_eq!((decoded.sequence, decoded.frame, fragment), (7, 3, &b"frame"[..]));
+
+		let mut corrupted = packet.clone();
+		corrupted[2] ^= 1;
+		assert!(matches!(decode(&corrupted), Err(PacketError::Checksum)));
+		assert!(matches!(decode(&packet[..3]), Err(PacketError::Truncated)));
+	}
+
+	#[test]
+	fn fragments_reassemble_out_of_order() {
+		let mut reassembler = Reassembler::default();
+
+		assert!(reassembler.push(&header(0, 0, 2, 3), b"c").is_none());
+		assert!(reassembler.push(&header(1, 0, 0, 3), b"a").is_none());
+
+		// a duplicate fragment doesn't count towards completing the frame
+		assert!(reassembler.push(&header(2, 0, 0, 3), b"a").is_none());
+
+		assert_eq!(reassembler.push(&header(3, 0, 1, 3), b"b"), Some(&b"abc"[..]));
+		assert_eq!((reassembler.completed, reassembler.incomplete), (1, 0));
+	}
+
+	#[test]
+	fn frames_missing_a_fragment_are_given_up_on() {
+		let mut reassembler = Reassembler::default();
+		assert!(reassembler.push(&header(0, 0, 0, 2), b"a").is_none());
+
+		for frame in 1..=MAX_PENDING_FRAMES as u32 {
+			assert!(reassembler.push(&header(frame, frame, 0, 2), b"a").is_none());
+		}
+
+		assert_eq!(reassembler.incomplete, 1);
+
+		// the missing fragment arriving late can't complete the frame which was given up on
+		assert!(reassembler.push(&header(100, 0, 1, 2), b"b").is_none());
+		assert_eq!(reassembler.completed, 0);
+	}
+
+	#[test]
+	fn restarted_frame_numbers_replace_stale_partial_frames() {
+		let mut reassembler = Reassembler::default();
+		assert!(reassembler.push(&header(10, 5, 0, 3), b"x").is_none());
+
+		// the sender restarted and reused the frame number for a frame of a different size
+		assert!(reassembler.push(&header(0, 5, 1, 2), b"b").is_none());
+		assert_eq!(reassembler.push(&header(1, 5, 0, 2), b"a"), Some(&b"ab"[..]));
+		assert_eq!((reassembler.completed, reassembler.incomplete), (1, 1));
+	}
+
+	#[test]
+	fn statistics_count_lost_reordered_and_duplicate_packets() {
+		let mut statistics = PacketStatistics::default();
+
+		for sequence in [0, 1, 4, 2, 2, 5] {
+			statistics.receive(&header(sequence, 0, 0, 1));
+		}
+
+		assert_eq!(statistics.received, 6);
+		assert_eq!(statistics.lost, 1);
+		assert_eq!(statistics.reordered, 1);
+		assert_eq!(statistics.duplicates, 1);
+	}
+}
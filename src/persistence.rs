@@ -0,0 +1,91 @@
+use jeflog::warn;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{env, fs::{self, File}, io::{self, Write}, path::{Path, PathBuf}, sync::OnceLock};
+
+/// The directory which persisted files and recordings are kept in.
+static DATA_DIRECTORY: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the directory which persisted files and recordings are kept in, which must be done
+/// before any are read or written. Defaults to the directory containing the executable.
+pub fn set_data_directory(directory: PathBuf) {
+	if DATA_DIRECTORY.set(directory).is_err() {
+		warn!("Data directory was set after it was already in use, so it was not changed.");
+	}
+}
+
+/// The path of a file or directory within the data directory.
+pub fn path(name: &str) -> PathBuf {
+	DATA_DIRECTORY
+		.get_or_init(|| {
+			env::current_exe()
+				.ok()
+				.and_then(|executable| executable.parent().map(Path::to_owned))
+				.unwrap_or_default()
+		})
+		.join(name)
+}
+
+/// Loads a value persisted to the given file in the data directory, or `None` if it was
+/// never persisted or can't be loaded, in which case a warning is logged.
+///
+/// A file which can't be decoded is moved aside so that it isn't overwritten by the next
+/// write and can still be recovered.
+pub fn load<T: DeserializeOwned>(file: &str) -> Option<T> {
+	let path = path(file);
+
+	let bytes = match fs::read(&path) {
+		Ok(bytes) => bytes,
+		Err(error) if error.kind() == io::ErrorKind::NotFound => return None,
+		Err(error) => {
+			warn!("Failed to read {}, continuing without it: {error}", path.display());
+			return None;
+		},
+	};
+
+	match postcard::from_bytes(&bytes) {
+		Ok(value) => Some(value),
+		Err(error) => {
+			let corrupt = with_suffix(&path, ".corrupt");
+			warn!("Failed to decode {}, moving it to {} and continuing without it: {error}", path.display(), corrupt.display());
+
+			if let Err(error) = fs::rename(&path, &corrupt) {
+				warn!("Failed to move {} aside: {error}", path.display());
+			}
+
+			None
+		},
+	}
+}
+
+/// Persists a value to the given file in the data directory, replacing whatever was
+/// persisted there.
+///
+/// The value is written to a temporary file which is then renamed over the original, so
+/// that a crash part way through never leaves a partially written file behind.
+pub fn persist<T: Serialize + ?Sized>(file: &str, value: &T) -> Result<(), String> {
+	let path = path(file);
+	let temporary = with_suffix(&path, ".tmp");
+
+	let serialized = postcard::to_allocvec(value)
+		.map_err(|error| error.to_string())?;
+
+	let write = || -> io::Result<()> {
+		if let Some(directory) = path.parent() {
+			fs::create_dir_all(directory)?;
+		}
+
+		let mut file = File::create(&temporary)?;
+		file.write_all(&serialized)?;
+		file.sync_all()?;
+		fs::rename(&temporary, &path)
+	};
+
+	write().map_err(|error| error.to_string())
+}
+
+/// Appends a suffix to the name of a file.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+	let mut path = path.as_os_str().to_owned();
+	path.push(suffix);
+	path.into()
+}
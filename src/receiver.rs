@@ -0,0 +1,115 @@
+use crate::{packet::{self, PacketStatistics, Reassembler}, TELEMETRY_BUFFER_SIZE, TELEMETRY_PORT};
+use jeflog::{fail, pass, task, warn};
+use std::{collections::HashMap, net::{SocketAddr, UdpSocket}, time::{Duration, Instant}};
+
+const USAGE: &str = "usage: flight receive [--port <port>]";
+
+/// How often the receiver prints statistics for each sender.
+const REPORT_PERIOD: Duration = Duration::from_secs(1);
+
+/// How a test receiver should listen for telemetry, as parsed from the command line.
+#[derive(Debug)]
+pub struct ReceiveOptions {
+	/// The UDP port which telemetry is received on.
+	port: u16,
+}
+
+impl ReceiveOptions {
+	/// Parses receive options from the arguments following `receive`.
+	pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+		let mut port = TELEMETRY_PORT;
+
+		while let Some(arg) = args.next() {
+			match arg.as_str() {
+				"--port" => {
+					port = args.next()
+						.and_then(|port| port.parse::<u16>().ok())
+						.ok_or(format!("--port requires a port number\n{USAGE}"))?;
+				},
+				_ => return Err(format!("unexpected argument '{arg}'\n{USAGE}")),
+			}
+		}
+
+		Ok(ReceiveOptions { port })
+	}
+}
+
+/// What has been received from a single sender.
+#[derive(Default)]
+struct Source {
+	statistics: PacketStatistics,
+	reassembler: Reassembler,
+
+	/// Mission-elapsed time of the latest packet.
+	elapsed: Duration,
+
+	/// Size of the largest reassembled frame.
+	largest_frame: usize,
+}
+
+/// Receives telemetry as the server would, periodically printing how many packets from
+/// each sender were received, lost, reordered, duplicated or corrupted and how many
+/// frames could be reassembled from them.
+pub fn receive(options: ReceiveOptions) {
+	let socket = match UdpSocket::bind(("0.0.0.0", options.port)) {
+		Ok(socket) => socket,
+		Err(error) => {
+			fail!("Failed to bind to UDP port {}: {error}", options.port);
+			return;
+		}
+	};
+
+	// wake up periodically so statistics are reported even when nothing arrives
+	if let Err(error) = socket.set_read_timeout(Some(REPORT_PERIOD)) {
+		fail!("Failed to set read timeout on receiver socket: {error}");
+		return;
+	}
+
+	task!("Receiving telemetry on port \x1b[1m{}\x1b[0m.", options.port);
+
+	let mut buffer = vec![0; TELEMETRY_BUFFER_SIZE];
+	let mut sources: HashMap<SocketAddr, Source> = HashMap::new();
+	let mut last_report = Instant::now();
+
+	loop {
+		if let Ok((size, sender)) = socket.recv_from(&mut buffer) {
+			let source = sources.entry(sender).or_default();
+
+			match packet::decode(&buffer[..size]) {
+				Ok((header, fragment)) => {
+					source.statistics.receive(&header);
+					source.elapsed = header.elapsed;
+
+					if let Some(frame) = source.reassembler.push(&header, fragment) {
+						source.largest_frame = source.largest_frame.max(frame.len());
+					}
+				},
+				Err(error) => {
+					source.statistics.reject();
+					warn!("Rejected packet from {sender}: {error}");
+				},
+			}
+		}
+
+		if last_report.elapsed() >= REPORT_PERIOD {
+			last_report = Instant::now();
+
+			for (sender, source) in &sources {
+				let summary = format!(
+					"{sender} at T+{:?}: {}; {} frames reassembled, {} incomplete, largest {} bytes",
+					source.elapsed,
+					source.statistics,
+					source.reassembler.completed,
+					source.reassembler.incomplete,
+					source.largest_frame,
+				);
+
+				if source.statistics.lost > 0 || source.statistics.corrupted > 0 {
+					warn!("{summary}");
+				} else {
+					pass!("{summary}");
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,270 @@
+use crate::{events::Event, inventory::RawDataPoint, lifecycle::SequenceRun, operator::{Limit, MappingOptions}, persistence, state::SharedState, RECORDING_DIRECTORY, RECORDING_FILE_SIZE, RECORDING_FLUSH_PERIOD, RECORDING_FLUSH_TIMEOUT, RECORDING_QUEUE_LENGTH, RECORDING_TOTAL_SIZE};
+use common::comm::{BoardId, CompositeValveState, Measurement, NodeMapping, SamControlMessage, Trigger};
+use jeflog::{fail, pass, warn};
+use serde::{Deserialize, Serialize};
+use std::{fs::{self, File}, io::{self, BufWriter, Write}, net::SocketAddr, sync::{atomic::{AtomicU64, Ordering}, mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError}}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+/// A single timestamped entry in a recording.
+///
+/// Recordings are a sequence of COBS-framed Postcard records, each delimited by a zero byte.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Record {
+	/// Time since the Unix epoch at which the entry was recorded.
+	pub time: Duration,
+
+	/// What was recorded.
+	pub entry: Entry,
+}
+
+/// Everything which may be recorded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Entry {
+	/// A raw, serialized `DataMessage` received by the switchboard.
+	DataMessage {
+		sender: SocketAddr,
+		message: Vec<u8>,
+	},
+
+	/// The readings and valve states changed by processing a batch of data points.
+	StateUpdate {
+		sensor_readings: Vec<(String, Measurement)>,
+		valve_states: Vec<(String, CompositeValveState)>,
+	},
+
+	/// Data points which matched no mapping, recorded while raw passthrough is enabled.
+	RawData(Vec<RawDataPoint>),
+
+	/// A command sent to a board.
+	Command(BoardId, SamControlMessage),
+
+	/// A transition of the program state, as displayed.
+	Transition(String),
+
+	/// Mappings received from the server, after persisted calibrations were applied.
+	Mappings(Vec<NodeMapping>),
+
+	/// Mapping options received from the server.
+	MappingOptions(Vec<MappingOptions>),
+
+	/// Limits received from the server.
+	Limits(Vec<Limit>),
+
+	/// A trigger received from the server.
+	Trigger(Trigger),
+
+	/// The vehicle was aborted.
+	Abort,
+
+	/// An event emitted on the flight computer.
+	Event(Event),
+
+	/// A sequence transitioned in its lifecycle.
+	SequenceRun(SequenceRun),
+}
+
+/// Messages sent to the recorder thread.
+pub enum Message {
+	Record(Record),
+
+	/// Flushes everything recorded so far to disk, replying once it has been written.
+	Flush(Sender<()>),
+}
+
+pub type RecordSender = SyncSender<Message>;
+
+/// Records dropped because the recorder fell behind, since it last caught up.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Spawns the recorder thread, which appends every record sent to it to the current
+/// recording file, and installs the sender used to submit records in the shared state.
+pub fn start(shared: &SharedState) -> io::Result<()> {
+	fs::create_dir_all(persistence::path(RECORDING_DIRECTORY))?;
+
+	let (record_tx, record_rx) = mpsc::sync_channel(RECORDING_QUEUE_LENGTH);
+	let mut recording = Recording::new()?;
+
+	*shared.recorder.lock().unwrap() = Some(record_tx);
+	shared.spawn("recorder", move || recorder(&mut recording, record_rx));
+	Ok(())
+}
+
+/// Records an entry, if the recorder is running.
+///
+/// Entries are dropped rather than queued without bound if the recorder falls behind, such
+/// as when the disk stalls.
+pub fn record(shared: &SharedState, entry: Entry) {
+	let recorder = shared.recorder.lock().unwrap();
+
+	let Some(recorder) = &*recorder else {
+		return;
+	};
+
+	// the recorder only stops if it failed, which was already reported
+	if let Err(TrySendError::Full(_)) = recorder.try_send(Message::Record(Record { time: since_epoch(), entry })) {
+		if DROPPED.fetch_add(1, Ordering::Relaxed) == 0 {
+			warn!("Recorder has fallen behind, dropping records until it catches up.");
+		}
+	}
+}
+
+/// Flushes everything recorded so far to disk, waiting up to `RECORDING_FLUSH_TIMEOUT` for
+/// it to be written.
+pub fn flush(shared: &SharedState) {
+	let (written_tx, written_rx) = mpsc::channel();
+
+	let Some(recorder) = shared.recorder.lock().unwrap().clone() else {
+		return;
+	};
+
+	match recorder.try_send(Message::Flush(written_tx)) {
+		Ok(()) => {},
+		Err(TrySendError::Full(_)) => {
+			warn!("Recorder has fallen behind, so the recording could not be flushed.");
+			return;
+		},
+		// the recorder only stops if it failed, which was already reported
+		Err(TrySendError::Disconnected(_)) => return,
+	}
+
+	match written_rx.recv_timeout(RECORDING_FLUSH_TIMEOUT) {
+		Ok(()) => {},
+		Err(RecvTimeoutError::Timeout) => warn!("Timed out after {RECORDING_FLUSH_TIMEOUT:?} waiting for the recording to be flushed."),
+		Err(RecvTimeoutError::Disconnected) => warn!("Recorder stopped before the recording could be flushed."),
+	}
+}
+
+fn since_epoch() -> Duration {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+}
+
+/// Receives records until every sender is dropped, flushing periodically so that at most
+/// `RECORDING_FLUSH_PERIOD` of data is lost if the process is killed.
+fn recorder(recording: &mut Recording, records: Receiver<Message>) {
+	let mut last_flush = Instant::now();
+
+	loop {
+		let message = records.recv_timeout(RECORDING_FLUSH_PERIOD.saturating_sub(last_flush.elapsed()));
+
+		if message.is_ok() {
+			let dropped = DROPPED.swap(0, Ordering::Relaxed);
+
+			if dropped > 0 {
+				warn!("Dropped {dropped} records while the recorder was behind.");
+			}
+		}
+
+		let result = match message {
+			Ok(Message::Record(record)) => recording.append(&record),
+			Ok(Message::Flush(written)) => recording.flush().map(|()| {
+				// whoever asked for the flush may have stopped waiting for it
+				let _ = written.send(());
+			}),
+			Err(RecvTimeoutError::Timeout) => Ok(()),
+			Err(RecvTimeoutError::Disconnected) => break,
+		};
+
+		if let Err(error) = result {
+			fail!("Failed to write to recording, stopping recorder: {error}");
+			return;
+		}
+
+		if last_flush.elapsed() >= RECORDING_FLUSH_PERIOD {
+			if let Err(error) = recording.flush() {
+				fail!("Failed to flush recording, stopping recorder: {error}");
+				return;
+			}
+
+			last_flush = Instant::now();
+		}
+	}
+
+	if let Err(error) = recording.flush() {
+		fail!("Failed to flush recording on shutdown: {error}");
+	}
+}
+
+/// The set of files which make up the current recording, rotated by size.
+struct Recording {
+	/// Seconds since the Unix epoch at which the recording started, which prefixes every file.
+	started: u64,
+
+	/// The index of the current file within the recording.
+	index: u32,
+
+	/// The current file, buffered.
+	file: BufWriter<File>,
+
+	/// How many bytes have been written to the current file.
+	size: u64,
+}
+
+impl Recording {
+	fn new() -> io::Result<Self> {
+		let started = since_epoch().as_secs();
+		let file = Self::create(started, 0)?;
+
+		Ok(Recording { started, index: 0, file, size: 0 })
+	}
+
+	/// Creates a recording file, making room for it within the total size cap first.
+	fn create(started: u64, index: u32) -> io::Result<BufWriter<File>> {
+		enforce_size_cap()?;
+
+		let path = persistence::path(RECORDING_DIRECTORY).join(format!("flight-{started}-{index:04}.rec"));
+		pass!("Recording to {}.", path.display());
+
+		Ok(BufWriter::new(File::create(path)?))
+	}
+
+	/// Appends a record, rotating to a new file if the current one is full.
+	fn append(&mut self, record: &Record) -> io::Result<()> {
+		// frame the record so that a reader can resynchronize after a truncated write
+		let framed = postcard::to_allocvec_cobs(record)
+			.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+		if self.size + framed.len() as u64 > RECORDING_FILE_SIZE {
+			self.file.flush()?;
+			self.index += 1;
+			self.file = Self::create(self.started, self.index)?;
+			self.size = 0;
+		}
+
+		self.file.write_all(&framed)?;
+		self.size += framed.len() as u64;
+		Ok(())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.file.flush()?;
+		self.file.get_ref().sync_data()
+	}
+}
+
+/// Deletes the oldest recording files until those remaining total less than `RECORDING_TOTAL_SIZE`,
+/// leaving room for a full file.
+fn enforce_size_cap() -> io::Result<()> {
+	let mut files = fs::read_dir(persistence::path(RECORDING_DIRECTORY))?
+		.filter_map(|entry| entry.ok())
+		.filter(|entry| entry.path().extension().is_some_and(|extension| extension == "rec"))
+		.filter_map(|entry| Some((entry.path(), entry.metadata().ok()?.len())))
+		.collect::<Vec<_>>();
+
+	// file names begin with the recording start time and index, so they sort oldest first
+	files.sort();
+
+	let mut total = files.iter().map(|(_, size)| size).sum::<u64>();
+
+	for (path, size) in files {
+		if total + RECORDING_FILE_SIZE <= RECORDING_TOTAL_SIZE {
+			break;
+		}
+
+		warn!("Deleting {} to stay within the recording size cap.", path.display());
+		fs::remove_file(path)?;
+		total -= size;
+	}
+
+	Ok(())
+}
@@ -0,0 +1,221 @@
+use crate::{builtins, handler::create_device_handler, recorder::{Entry, Record}, state::{self, SharedState}, switchboard::Worker, MIN_REPLAY_SPEED};
+use common::{comm::{BoardId, DataMessage, NodeMapping, SamControlMessage}, sequence};
+use jeflog::{fail, pass, task, warn};
+use std::{fs, path::{Path, PathBuf}, sync::mpsc, thread, time::{Duration, Instant}};
+
+const USAGE: &str = "usage: flight replay <recording> [--speed <factor>] [--triggers] [--mappings <file.json>]";
+
+/// How a recording should be replayed, as parsed from the command line.
+#[derive(Debug)]
+pub struct ReplayOptions {
+	/// A recording file, or a directory whose recording files are replayed in name order.
+	path: PathBuf,
+
+	/// How many times faster than real time to replay, or zero to replay as fast as possible.
+	speed: f64,
+
+	/// Whether triggers in the recording are evaluated against the replayed vehicle state.
+	triggers: bool,
+
+	/// A JSON file of mappings to use in place of those in the recording.
+	mappings: Option<PathBuf>,
+}
+
+impl ReplayOptions {
+	/// Parses replay options from the arguments following `replay`.
+	pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+		let mut path = None;
+		let mut speed = 1.0;
+		let mut triggers = false;
+		let mut mappings = None;
+
+		while let Some(arg) = args.next() {
+			match arg.as_str() {
+				"--speed" => {
+					speed = args.next()
+						.and_then(|speed| speed.parse::<f64>().ok())
+						.filter(|speed| *speed == 0.0 || (speed.is_finite() && *speed >= MIN_REPLAY_SPEED))
+						.ok_or(format!("--speed requires zero or a finite number of at least {MIN_REPLAY_SPEED}\n{USAGE}"))?;
+				},
+				"--triggers" => triggers = true,
+				"--mappings" => {
+					mappings = Some(args.next().ok_or(format!("--mappings requires a file\n{USAGE}"))?.into());
+				},
+				_ if path.is_none() => path = Some(arg.into()),
+				_ => return Err(format!("unexpected argument '{arg}'\n{USAGE}")),
+			}
+		}
+
+		let path = path.ok_or(USAGE.to_owned())?;
+		Ok(ReplayOptions { path, speed, triggers, mappings })
+	}
+}
+
+/// Replays the board traffic of a recording through the worker, reproducing the vehicle
+/// state as it evolved during the recording without any boards present.
+///
+/// Commands which sequences and triggers would have sent to boards are logged instead.
+pub fn replay(options: ReplayOptions) {
+	let records = match read_records(&options.path) {
+		Ok(records) => records,
+		Err(error) => {
+			fail!("Failed to read recording at {}: {error}", options.path.display());
+			return;
+		}
+	};
+
+	let mappings_override = match &options.mappings {
+		Some(path) => match read_mappings(path) {
+			Ok(mappings) => Some(mappings),
+			Err(error) => {
+				fail!("Failed to read mappings from {}: {error}", path.display());
+				return;
+			}
+		},
+		None => None,
+	};
+
+	// replays are never recorded themselves
+	let shared = SharedState::new();
+
+	if let Some(mappings) = mappings_override {
+		*shared.mappings.lock().unwrap() = mappings;
+	}
+
+	let (command_tx, command_rx) = mpsc::channel::<(BoardId, SamControlMessage)>();
+
+	thread::spawn(move || {
+		for (board_id, command) in command_rx {
+			task!("Replay would have sent {command:?} to {board_id}.");
+		}
+	});
+
+	sequence::initialize(shared.mappings.clone());
+	sequence::set_device_handler(create_device_handler(shared.clone(), command_tx));
+
+	if let Err(error) = builtins::initialize(shared.clone()) {
+		fail!("Failed to register sequence builtins: {error}");
+	}
+
+	if options.triggers {
+		thread::spawn(state::check_triggers(&shared));
+	}
+
+	let Some(first) = records.first().map(|record| record.time) else {
+		warn!("Recording at {} is empty.", options.path.display());
+		return;
+	};
+
+	task!("Replaying {} records at {}x speed.", records.len(), options.speed);
+
+	let mut worker = Worker::default();
+	let started = Instant::now();
+	let mut messages = 0;
+
+	for record in records {
+		let elapsed = record.time.saturating_sub(first);
+
+		// wait until the record is due, relative to the start of the recording
+		if options.speed > 0.0 {
+			// a record implausibly far into the recording is replayed immediately rather than overflowing
+			let due = Duration::try_from_secs_f64(elapsed.as_secs_f64() / options.speed)
+				.ok()
+				.and_then(|delay| started.checked_add(delay))
+				.unwrap_or(started);
+
+			let now = Instant::now();
+
+			if due > now {
+				thread::sleep(due - now);
+			}
+		}
+
+		// the worker ages readings by the time they were recorded, whatever the replay speed
+		shared.clock.set(started + elapsed);
+
+		match record.entry {
+			Entry::DataMessage { message, .. } => {
+				match postcard::from_bytes::<DataMessage>(&message) {
+					Ok(DataMessage::Sam(board_id, datapoints)) => {
+						worker.process(&shared, board_id, datapoints.to_vec());
+						messages += 1;
+					},
+					Ok(_) => {},
+					Err(error) => warn!("Skipping recorded data message which could not be deserialized: {error}"),
+				}
+			},
+			Entry::Mappings(mappings) if options.mappings.is_none() => {
+				*shared.mappings.lock().unwrap() = mappings;
+			},
+			Entry::MappingOptions(mapping_options) => {
+				*shared.mapping_options.lock().unwrap() = mapping_options
+					.into_iter()
+					.map(|options| (options.text_id.clone(), options))
+					.collect();
+			},
+			Entry::Limits(limits) => *shared.limits.lock().unwrap() = limits,
+			Entry::Trigger(trigger) => state::set_trigger(&shared, trigger),
+			Entry::Transition(transition) => task!("Recording transitioned to state: {transition}"),
+			Entry::Abort => warn!("Recording aborted here."),
+			_ => {},
+		}
+	}
+
+	pass!("Replayed {messages} data messages in {:?}.", started.elapsed());
+	pass!("Final vehicle state: {:#?}", *shared.vehicle_state.lock().unwrap());
+
+	// list what was left out of the vehicle state to help author mappings for new boards
+	let inventory = shared.channel_inventory.lock().unwrap().summarize(shared.clock.now());
+
+	for channel in inventory.iter().filter(|channel| !channel.mapped) {
+		warn!(
+			"Unmapped channel {} on {} ({:?}): {} data points, last value {}.",
+			channel.channel,
+			channel.board_id,
+			channel.channel_type,
+			channel.count,
+			channel.last_value,
+		);
+	}
+}
+
+/// Reads every record from a recording file or, for a directory, from each of its recording files in name order.
+fn read_records(path: &Path) -> Result<Vec<Record>, String> {
+	let mut files = Vec::new();
+
+	if path.is_dir() {
+		for entry in fs::read_dir(path).map_err(|error| error.to_string())? {
+			let file = entry.map_err(|error| error.to_string())?.path();
+
+			if file.extension().is_some_and(|extension| extension == "rec") {
+				files.push(file);
+			}
+		}
+
+		files.sort();
+	} else {
+		files.push(path.to_owned());
+	}
+
+	let mut records = Vec::new();
+
+	for file in files {
+		let mut bytes = fs::read(&file).map_err(|error| error.to_string())?;
+
+		// each record is COBS-framed and delimited by a zero byte
+		for frame in bytes.split_mut(|byte| *byte == 0).filter(|frame| !frame.is_empty()) {
+			match postcard::from_bytes_cobs::<Record>(frame) {
+				Ok(record) => records.push(record),
+				Err(error) => warn!("Skipping corrupt record in {}: {error}", file.display()),
+			}
+		}
+	}
+
+	Ok(records)
+}
+
+/// Reads a JSON array of mappings.
+fn read_mappings(path: &Path) -> Result<Vec<NodeMapping>, String> {
+	let json = fs::read_to_string(path).map_err(|error| error.to_string())?;
+	serde_json::from_str(&json).map_err(|error| error.to_string())
+}
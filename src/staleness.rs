@@ -0,0 +1,26 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+/// When a sensor reading was last received and how long it remains valid afterwards.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadingTimestamp {
+	/// The instant at which the worker processed the reading.
+	pub received: Instant,
+
+	/// How long after `received` the reading is considered stale.
+	pub stale_after: Duration,
+}
+
+impl ReadingTimestamp {
+	/// Whether the reading has gone without an update for longer than its threshold as of `now`.
+	pub fn is_stale(&self, now: Instant) -> bool {
+		now.saturating_duration_since(self.received) > self.stale_after
+	}
+}
+
+/// Iterates over the names of all readings which are stale as of `now`.
+pub fn stale_readings(timestamps: &HashMap<String, ReadingTimestamp>, now: Instant) -> impl Iterator<Item = &String> {
+	timestamps
+		.iter()
+		.filter(move |(_, timestamp)| timestamp.is_stale(now))
+		.map(|(name, _)| name)
+}
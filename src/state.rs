@@ -1,11 +1,14 @@
-use common::{comm::{Computer, FlightControlMessage, NodeMapping, Sequence, VehicleState}, sequence};
+use common::{comm::{BoardId, Computer, FlightControlMessage, NodeMapping, Sequence, Trigger, VehicleState}, sequence};
 use jeflog::{task, pass, warn, fail};
 use postcard::experimental::max_size::MaxSize;
-use std::{fmt, io::{self, Read, Write}, net::{IpAddr, TcpStream, UdpSocket}, sync::{Arc, Mutex}, thread::{self, ThreadId}, time::Duration};
+use std::{collections::{HashMap, HashSet}, fmt, io::{self, Read, Write}, net::{IpAddr, TcpStream, UdpSocket}, process, sync::{Arc, Condvar, Mutex}, thread::{self, JoinHandle, ThreadId}, time::{Duration, Instant}};
 use bimap::BiHashMap;
-use crate::{forwarder, handler::{self, create_device_handler}, switchboard, SWITCHBOARD_ADDRESS, SERVO_PORT};
+use crate::{builtins, calibration, clock::Clock, countdown::{self, Countdown}, events::{self, EventKind, EventSender, Severity}, lifecycle::{self, SequenceRun, SequenceStatus}, forwarder::{self, ForwarderConfig, TelemetryDestination}, handler::{self, create_device_handler}, history::SensorHistory, inventory::ChannelInventory, library, operator::{self, Limit, MappingOptions}, recorder::{self, RecordSender}, staleness::ReadingTimestamp, switchboard, validation, FORWARDING_PERIOD, KEYFRAME_PERIOD, LATCHED_TRIGGER_POLL_PERIOD, SWITCHBOARD_ADDRESS, SERVO_PORT, TELEMETRY_PORT};
 use pyo3::Python;
 
+/// A background thread spawned through `SharedState::spawn`, along with its name.
+pub type NamedThread = (&'static str, JoinHandle<()>);
+
 /// Holds all shared state that should be accessible concurrently in multiple contexts.
 /// 
 /// Everything in this struct should be wrapped with `Arc<Mutex<T>>`. **Do not abuse this struct.**
@@ -15,9 +18,87 @@ pub struct SharedState {
 	pub vehicle_state: Arc<Mutex<VehicleState>>,
 	pub mappings: Arc<Mutex<Vec<NodeMapping>>>,
 	pub server_address: Arc<Mutex<Option<IpAddr>>>,
-	pub triggers: Arc<Mutex<Vec<common::comm::Trigger>>>,
+	pub triggers: Arc<Mutex<Vec<Trigger>>>,
 	pub sequences: Arc<Mutex<BiHashMap<String, ThreadId>>>,
 	pub abort_sequence: Arc<Mutex<Option<Sequence>>>,
+	pub mapping_options: Arc<Mutex<HashMap<String, MappingOptions>>>,
+	pub reading_timestamps: Arc<Mutex<HashMap<String, ReadingTimestamp>>>,
+	pub limits: Arc<Mutex<Vec<Limit>>>,
+	pub sensor_history: Arc<Mutex<HashMap<String, SensorHistory>>>,
+	pub server_link: Arc<Mutex<Option<TcpStream>>>,
+	pub forwarder_config: Arc<Mutex<ForwarderConfig>>,
+	pub recorder: Arc<Mutex<Option<RecordSender>>>,
+	pub telemetry_destinations: Arc<Mutex<Vec<TelemetryDestination>>>,
+
+	pub channel_inventory: Arc<Mutex<ChannelInventory>>,
+	pub raw_passthrough: Arc<Mutex<bool>>,
+	pub connected_boards: Arc<Mutex<HashSet<BoardId>>>,
+	pub threads: Arc<Mutex<Vec<NamedThread>>>,
+	pub program_state: Arc<Mutex<&'static str>>,
+	pub events: Arc<Mutex<Option<EventSender>>>,
+	pub valve_claims: Arc<Mutex<HashMap<String, String>>>,
+	pub sequence_runs: Arc<Mutex<HashMap<u64, SequenceRun>>>,
+
+	/// Notified by the worker whenever it updates `vehicle_state`, for waiting on that lock.
+	pub vehicle_updated: Arc<Condvar>,
+
+	pub library: Arc<Mutex<HashMap<String, Sequence>>>,
+	pub countdown: Arc<Mutex<Countdown>>,
+
+	/// Set once the vehicle aborts, until the operator re-arms it.
+	pub abort_latched: Arc<Mutex<bool>>,
+
+	/// The time against which data from boards is timestamped, which a replay controls.
+	pub clock: Clock,
+
+	/// When the flight computer started, from which mission-elapsed time is measured.
+	pub started: Instant,
+}
+
+impl SharedState {
+	/// Constructs the shared state as it is before any operator commands are received.
+	pub fn new() -> Self {
+		SharedState {
+			vehicle_state: Arc::new(Mutex::new(VehicleState::new())),
+			mappings: Arc::new(Mutex::new(Vec::new())),
+			server_address: Arc::new(Mutex::new(None)),
+			triggers: Arc::new(Mutex::new(Vec::new())),
+			sequences: Arc::new(Mutex::new(BiHashMap::new())),
+			abort_sequence: Arc::new(Mutex::new(None)),
+			mapping_options: Arc::new(Mutex::new(HashMap::new())),
+			reading_timestamps: Arc::new(Mutex::new(HashMap::new())),
+			limits: Arc::new(Mutex::new(Vec::new())),
+			sensor_history: Arc::new(Mutex::new(HashMap::new())),
+			server_link: Arc::new(Mutex::new(None)),
+			forwarder_config: Arc::new(Mutex::new(ForwarderConfig { period: FORWARDING_PERIOD, port: TELEMETRY_PORT, keyframe_period: KEYFRAME_PERIOD })),
+			recorder: Arc::new(Mutex::new(None)),
+			telemetry_destinations: Arc::new(Mutex::new(forwarder::load_destinations())),
+			channel_inventory: Arc::new(Mutex::new(ChannelInventory::default())),
+			raw_passthrough: Arc::new(Mutex::new(false)),
+			connected_boards: Arc::new(Mutex::new(HashSet::new())),
+			threads: Arc::new(Mutex::new(Vec::new())),
+			program_state: Arc::new(Mutex::new("Init")),
+			events: Arc::new(Mutex::new(None)),
+			valve_claims: Arc::new(Mutex::new(HashMap::new())),
+			sequence_runs: Arc::new(Mutex::new(HashMap::new())),
+			vehicle_updated: Arc::new(Condvar::new()),
+			library: Arc::new(Mutex::new(library::load())),
+			countdown: Arc::new(Mutex::new(Countdown::default())),
+			abort_latched: Arc::new(Mutex::new(false)),
+			clock: Clock::default(),
+			started: Instant::now(),
+		}
+	}
+
+	/// Spawns a named background thread whose liveness is reported in metrics.
+	pub fn spawn(&self, name: &'static str, f: impl FnOnce() + Send + 'static) {
+		let handle = thread::Builder::new()
+			.name(name.to_owned())
+			.spawn(f)
+			.expect("failed to spawn thread");
+
+		self.threads.lock().unwrap().push((name, handle));
+	}
 }
 
 
@@ -68,6 +149,31 @@ impl ProgramState {
 	}
 }
 
+impl ProgramState {
+	/// The name of every state, as returned by `name`.
+	pub const NAMES: [&'static str; 4] = ["Init", "ServerDiscovery", "WaitForOperator", "RunSequence"];
+
+	/// The name of this state, without any of its details.
+	pub fn name(&self) -> &'static str {
+		match self {
+			ProgramState::Init => "Init",
+			ProgramState::ServerDiscovery { .. } => "ServerDiscovery",
+			ProgramState::WaitForOperator { .. } => "WaitForOperator",
+			ProgramState::RunSequence { .. } => "RunSequence",
+		}
+	}
+
+	/// The shared flight state, if it has been created yet.
+	pub fn shared(&self) -> Option<&SharedState> {
+		match self {
+			ProgramState::Init => None,
+			ProgramState::ServerDiscovery { shared }
+				| ProgramState::WaitForOperator { shared, .. }
+				| ProgramState::RunSequence { shared, .. } => Some(shared),
+		}
+	}
+}
+
 impl fmt::Display for ProgramState {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
@@ -90,16 +196,15 @@ impl fmt::Display for ProgramState {
 
 fn init() -> ProgramState {
 	let home_socket = UdpSocket::bind(SWITCHBOARD_ADDRESS)
-		.expect(&format!("Cannot create bind on address {:#?}", SWITCHBOARD_ADDRESS));
-
-	let shared = SharedState {
-		vehicle_state: Arc::new(Mutex::new(VehicleState::new())),
-		mappings: Arc::new(Mutex::new(Vec::new())),
-		server_address: Arc::new(Mutex::new(None)),
-		triggers: Arc::new(Mutex::new(Vec::new())),
-		sequences: Arc::new(Mutex::new(BiHashMap::new())),
-		abort_sequence: Arc::new(Mutex::new(None)),
-	};
+		.unwrap_or_else(|_| panic!("Cannot create bind on address {:#?}", SWITCHBOARD_ADDRESS));
+
+	let shared = SharedState::new();
+
+	if let Err(error) = recorder::start(&shared) {
+		fail!("Failed to start recorder, continuing without recording: {error}");
+	}
+
+	events::start(&shared);
 
 	let command_tx = 
 		match switchboard::start(shared.clone(), home_socket) {
@@ -111,9 +216,30 @@ fn init() -> ProgramState {
 	};
 
 	sequence::initialize(shared.mappings.clone());
-	sequence::set_device_handler(create_device_handler(shared.clone(), command_tx));
+	sequence::set_device_handler(create_device_handler(shared.clone(), command_tx.clone()));
+
+	if let Err(error) = builtins::initialize(shared.clone()) {
+		fail!("Failed to register sequence builtins: {error}");
+	}
+
+	shared.spawn("triggers", check_triggers(&shared));
+	shared.spawn("countdown", countdown::run(shared.clone(), command_tx));
 
-	thread::spawn(check_triggers(&shared));
+	// additional destinations are forwarded to regardless of whether the server is connected
+	shared.spawn("forwarder", forwarder::forward_vehicle_state(&shared));
+
+	let signaled = shared.clone();
+
+	// flush the recording on an interrupt or termination so nothing leading up to it is lost
+	let handled = ctrlc::set_handler(move || {
+		warn!("Received shutdown signal. Flushing recording and exiting...");
+		recorder::flush(&signaled);
+		process::exit(0);
+	});
+
+	if let Err(error) = handled {
+		warn!("Failed to handle shutdown signals, so the recording may not be flushed on exit: {error}");
+	}
 
 	ProgramState::ServerDiscovery { shared }
 }
@@ -167,8 +293,13 @@ fn server_discovery(shared: SharedState) -> ProgramState {
 			continue;
 		}
 
+		// a second handle to the stream lets other threads send reports while this one waits for commands
+		match stream.try_clone() {
+			Ok(link) => *shared.server_link.lock().unwrap() = Some(link),
+			Err(error) => warn!("Failed to clone server stream for reports: {error}"),
+		}
+
 		*shared.server_address.lock().unwrap() = Some(stream.peer_addr().unwrap().ip());
-		thread::spawn(forwarder::forward_vehicle_state(&shared));
 
 		return ProgramState::WaitForOperator { server_socket: stream, shared };
 	}
@@ -184,20 +315,34 @@ fn wait_for_operator(mut server_socket: TcpStream, shared: SharedState) -> Progr
 		Ok(size) => {
 			// if the size is zero, a TCP shutdown packet was sent. the connection is closed.
 			if size == 0 {
+				*shared.server_link.lock().unwrap() = None;
 				return ProgramState::ServerDiscovery { shared };
 			}
 
+			// extended commands are tagged so they are never mistaken for a FlightControlMessage
+			if buffer[0] == operator::EXTENDED_COMMAND_TAG {
+				return operator::handle_command(&buffer[1..size], server_socket, shared);
+			}
+
 			match postcard::from_bytes::<FlightControlMessage>(&buffer) {
 				Ok(message) => {
 					match message {
-						FlightControlMessage::Mappings(mappings) => {
+						FlightControlMessage::Mappings(mut mappings) => {
 							pass!("Received mappings from server: {mappings:#?}");
+							calibration::apply_persisted(&mut mappings);
+							recorder::record(&shared, recorder::Entry::Mappings(mappings.clone()));
 							*shared.mappings.lock().unwrap() = mappings;
+							library::validate(&shared);
 							ProgramState::WaitForOperator { server_socket, shared }
 						},
 						FlightControlMessage::Sequence(sequence) => {
 							pass!("Received sequence from server: {sequence:#?}");
 
+							if !validation::check(&shared, &sequence) {
+								fail!("Rejected sequence '{}' because it failed validation.", sequence.name);
+								return ProgramState::WaitForOperator { server_socket, shared };
+							}
+
 							// if the abort sequence was set, don't run it
 							// set the shared abort sequence and return early
 							if sequence.name == "abort" {
@@ -209,24 +354,8 @@ fn wait_for_operator(mut server_socket: TcpStream, shared: SharedState) -> Progr
 						},
 						FlightControlMessage::Trigger(trigger) => {
 							pass!("Received trigger from server: {trigger:#?}");
-							
-							// update existing trigger if one has the same name
-							// otherwise, add a new trigger to the vec
-							let mut triggers = shared.triggers.lock().unwrap();
-
-							let existing = triggers
-								.iter()
-								.position(|t| t.name == trigger.name);
-
-							if let Some(index) = existing {
-								triggers[index] = trigger;
-							} else {
-								triggers.push(trigger);
-							}
-
-							// necessary to allow passing 'shared' back to WaitForOperator
-							drop(triggers);
-
+							recorder::record(&shared, recorder::Entry::Trigger(trigger.clone()));
+							set_trigger(&shared, trigger);
 							ProgramState::WaitForOperator { server_socket, shared }
 						},
 						FlightControlMessage::StopSequence(name) => {
@@ -237,6 +366,7 @@ fn wait_for_operator(mut server_socket: TcpStream, shared: SharedState) -> Progr
 								.remove_by_left(&name);
 
 							if stopped.is_some() {
+								lifecycle::halt(&shared, lifecycle::interrupt(&shared, &name, SequenceStatus::Stopped));
 								pass!("Stopped sequence '{name}'.");
 							} else {
 								warn!("Sequence '{name}' was not running.");
@@ -262,36 +392,54 @@ fn wait_for_operator(mut server_socket: TcpStream, shared: SharedState) -> Progr
 		},
 		Err(error) => {
 			fail!("Failed to read from server socket: {}. Dropping connection.", error.to_string());
+			*shared.server_link.lock().unwrap() = None;
 			ProgramState::ServerDiscovery { shared }
 		}
 	}
 }
 
 /// Spawns a thread which runs the specified sequence before returning to `WaitForOperator`.
+///
+/// A sequence with the same name as one already running is rejected.
 fn run_sequence(server_socket: TcpStream, sequence: Sequence, shared: SharedState) -> ProgramState {
-	let sequence_name = sequence.name.clone();
+	if let Err(error) = lifecycle::spawn(&shared, sequence, None, false) {
+		warn!("Rejected sequence: {error}.");
+	}
 
-	let thread_id = thread::spawn(|| sequence::run(sequence))
-		.thread()
-		.id();
+	ProgramState::WaitForOperator { server_socket, shared }
+}
 
-	shared.sequences
-		.lock()
-		.unwrap()
-		.insert(sequence_name, thread_id);
+/// Adds a trigger, replacing any existing trigger with the same name.
+pub fn set_trigger(shared: &SharedState, trigger: Trigger) {
+	let mut triggers = shared.triggers.lock().unwrap();
 
-	ProgramState::WaitForOperator { server_socket, shared }
+	let existing = triggers
+		.iter()
+		.position(|t| t.name == trigger.name);
+
+	if let Some(index) = existing {
+		triggers[index] = trigger;
+	} else {
+		triggers.push(trigger);
+	}
 }
 
 /// Constructs a closure which continuously checks if any triggers have tripped,
 /// running the corresponding script inline if so.
-fn check_triggers(shared: &SharedState) -> impl FnOnce() -> () {
+pub fn check_triggers(shared: &SharedState) -> impl FnOnce() {
+	let shared = shared.clone();
 	let triggers = shared.triggers.clone();
 
 	// return closure instead of using the function itself because of borrow-checking
 	// rules regarding moving the 'triggers' reference across closure bounds
 	move || {
 		loop {
+			// triggers are suspended while aborted so that they can't undo the abort
+			if *shared.abort_latched.lock().unwrap() {
+				thread::sleep(LATCHED_TRIGGER_POLL_PERIOD);
+				continue;
+			}
+
 			let mut triggers = triggers.lock().unwrap();
 
 			for trigger in triggers.iter_mut() {
@@ -305,6 +453,8 @@ fn check_triggers(shared: &SharedState) -> impl FnOnce() -> () {
 
 				// checks if the condition evaluated true
 				if check.as_ref().is_ok_and(|c| *c) {
+					events::emit(&shared, Severity::Info, module_path!(), EventKind::TriggerFired(trigger.name.clone()));
+
 					let sequence = Sequence {
 						name: format!("trigger_{}", trigger.name),
 						script: trigger.script.clone(),
@@ -313,12 +463,17 @@ fn check_triggers(shared: &SharedState) -> impl FnOnce() -> () {
 					// run sequence in the same thread so there is no rapid-fire
 					// sequence dispatches if a trigger is tripped
 					// note: this is intentionally blocking
-					common::sequence::run(sequence);
+					let _ = handler::run_sequence(&shared, &sequence, None);
 				}
 
 				if let Err(error) = check {
 					fail!("Trigger '{}' raised exception during execution: {error}", trigger.name);
 					trigger.active = false;
+
+					events::emit(&shared, Severity::Warning, module_path!(), EventKind::TriggerFailed {
+						name: trigger.name.clone(),
+						error: error.to_string(),
+					});
 				}
 			}
 
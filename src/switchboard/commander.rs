@@ -1,10 +1,10 @@
 use std::{collections::HashMap, net::{SocketAddr, UdpSocket}, sync::{mpsc::Receiver, Arc, RwLock}};
 use common::comm::{BoardId, SamControlMessage};
 use jeflog::{fail, pass};
-use crate::{handler, state::SharedState, COMMAND_MESSAGE_BUFFER_SIZE, SAM_PORT};
+use crate::{events::{self, EventKind, Severity}, handler, recorder, state::SharedState, COMMAND_MESSAGE_BUFFER_SIZE, SAM_PORT};
 
 /// "fast lane" for sending SamControlMessages. Only wakes up when there's a command to be sent.
-pub fn commander(shared: SharedState, commands: Receiver<(BoardId, SamControlMessage)>, sender: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>) -> impl FnOnce() -> () {
+pub fn commander(shared: SharedState, commands: Receiver<(BoardId, SamControlMessage)>, sender: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>) -> impl FnOnce() {
   move || {
     let mut buffer = [0; COMMAND_MESSAGE_BUFFER_SIZE];
 
@@ -25,6 +25,8 @@ pub fn commander(shared: SharedState, commands: Receiver<(BoardId, SamControlMes
 
         match sender.send_to(message, socket) {
           Ok(_) => {
+            recorder::record(&shared, recorder::Entry::Command(board_id.clone(), command.clone()));
+
             match command {
               SamControlMessage::ActuateValve { channel, powered } => {
                 pass!("The command was sent successfully: {} {board_id}'s channel {channel} valve.", if powered { "Power" } else { "Unpower" });
@@ -34,10 +36,14 @@ pub fn commander(shared: SharedState, commands: Receiver<(BoardId, SamControlMes
               },
             }
           },
-          Err(e) => fail!("Couldn't send control message to board {board_id} via socket {socket:#?}: {e}"),
+          Err(e) => {
+            fail!("Couldn't send control message to board {board_id} via socket {socket:#?}: {e}");
+            events::emit(&shared, Severity::Warning, module_path!(), EventKind::CommandFailed { board_id, reason: e.to_string() });
+          },
         };
       } else {
         fail!("Couldn't find socket with board ID {board_id} in sockets HashMap.");
+        events::emit(&shared, Severity::Warning, module_path!(), EventKind::CommandFailed { board_id, reason: "board is not connected".to_owned() });
       }
     }
 
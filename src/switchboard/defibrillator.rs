@@ -4,7 +4,7 @@ use jeflog::fail;
 use crate::{handler, state::SharedState, HEARTBEAT_PERIOD};
 
 /// Wakes every HEARTBEAT_RATE to send heartbeats to all the connected Sam boards to ensure that the FC isn't disconnected.
-pub fn defibrillator(shared: SharedState, sender: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>, statuses: Arc<Mutex<HashSet<BoardId>>>) -> impl FnOnce() -> () {
+pub fn defibrillator(shared: SharedState, sender: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>, statuses: Arc<Mutex<HashSet<BoardId>>>) -> impl FnOnce() {
   move || {
     let mut buf = vec![0; crate::HEARTBEAT_BUFFER_SIZE];
 
@@ -1,10 +1,10 @@
 use std::{collections::{HashMap, HashSet}, sync::{mpsc::{Receiver, TryRecvError}, Arc, Mutex}, time::Instant};
 use common::comm::BoardId;
 use jeflog::fail;
-use crate::{handler, state::SharedState, REFRESH_COUNT, TIME_TIL_DEATH};
+use crate::{events::{self, EventKind, Severity}, handler, state::SharedState, REFRESH_COUNT, TIME_TIL_DEATH};
 
 /// Tracks the state of each board, detected if boards lose communications.
-pub fn lifetime(shared: SharedState, snooze: Receiver<BoardId>, statuses: Arc<Mutex<HashSet<BoardId>>>) -> impl FnOnce() -> () {  
+pub fn lifetime(shared: SharedState, snooze: Receiver<BoardId>, statuses: Arc<Mutex<HashSet<BoardId>>>) -> impl FnOnce() {  
   move || {
     let mut timers = HashMap::new();
 
@@ -46,6 +46,7 @@ pub fn lifetime(shared: SharedState, snooze: Receiver<BoardId>, statuses: Arc<Mu
           //};
 
           fail!("Detected loss of comms from {board_id}");
+          events::emit(&shared, Severity::Critical, module_path!(), EventKind::BoardLost(board_id.clone()));
         }
       }
 
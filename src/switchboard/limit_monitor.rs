@@ -0,0 +1,205 @@
+use std::{collections::HashMap, time::Instant};
+use common::comm::Sequence;
+use jeflog::{fail, warn};
+use crate::{events::{self, EventKind, Severity}, lifecycle, operator::{Limit, LimitAction, LimitSeverity}, state::SharedState};
+
+/// A continuous violation of a single limit.
+struct Excursion {
+	/// When the reading first violated the limit.
+	since: Instant,
+
+	/// Whether the limit has already tripped during this excursion.
+	tripped: bool,
+}
+
+/// Evaluates red-line limits against the latest sensor readings.
+#[derive(Default)]
+pub struct LimitMonitor {
+	/// The limits as of the last check, so that excursions are forgotten once they are replaced.
+	limits: Vec<Limit>,
+
+	/// Ongoing excursions by the index of their limit, so that several limits on the same
+	/// reading are each tracked separately.
+	excursions: HashMap<usize, Excursion>,
+}
+
+impl LimitMonitor {
+	/// Checks every limit against the latest readings, tripping each limit once per excursion
+	/// after it has persisted. Returns `true` if a tripped limit requires an abort.
+	pub fn check(&mut self, shared: &SharedState) -> bool {
+		let vehicle_state = shared.vehicle_state.lock().unwrap();
+		let reading_timestamps = shared.reading_timestamps.lock().unwrap();
+		let limits = shared.limits.lock().unwrap();
+		let now = shared.clock.now();
+
+		let tripped = self.evaluate(&limits, now, |text_id| {
+			// stale readings neither trip nor clear a limit
+			let fresh = reading_timestamps
+				.get(text_id)
+				.is_some_and(|timestamp| !timestamp.is_stale(now));
+
+			vehicle_state.sensor_readings
+				.get(text_id)
+				.filter(|_| fresh)
+				.map(|measurement| measurement.value)
+		});
+
+		let mut contingencies = Vec::new();
+		let mut abort = false;
+
+		for index in tripped {
+			let limit = &limits[index];
+			let measurement = &vehicle_state.sensor_readings[&limit.text_id];
+
+			let severity = match limit.severity {
+				LimitSeverity::Caution => {
+					warn!("Caution limit tripped: '{}' is {} {}.", limit.text_id, measurement.value, measurement.unit);
+					Severity::Warning
+				},
+				LimitSeverity::Warning => {
+					fail!("Warning limit tripped: '{}' is {} {}.", limit.text_id, measurement.value, measurement.unit);
+					Severity::Critical
+				},
+			};
+
+			events::emit(shared, severity, module_path!(), EventKind::LimitTripped {
+				text_id: limit.text_id.clone(),
+				severity: limit.severity,
+				value: measurement.value,
+			});
+
+			match &limit.action {
+				LimitAction::Alarm => {},
+				LimitAction::RunSequence(sequence) => contingencies.push(sequence.clone()),
+				LimitAction::Abort => abort = true,
+			}
+		}
+
+		// spawning reports to the server, so nothing the telemetry paths wait on may be held
+		drop(limits);
+		drop(reading_timestamps);
+		drop(vehicle_state);
+
+		// a replay reproduces the limits which tripped without acting on them
+		if shared.clock.is_replaying() {
+			if abort || !contingencies.is_empty() {
+				warn!("Replay is not running contingencies or aborting for tripped limits.");
+			}
+
+			return false;
+		}
+
+		// an abort supersedes any contingency sequences
+		if !abort {
+			for sequence in contingencies {
+				run_contingency(shared, sequence);
+			}
+		}
+
+		abort
+	}
+
+	/// Updates the excursion of every limit given the latest fresh value of each reading, if
+	/// any, returning the index of every limit which trips.
+	fn evaluate(&mut self, limits: &[Limit], now: Instant, value: impl Fn(&str) -> Option<f64>) -> Vec<usize> {
+		if self.limits != limits {
+			self.excursions.clear();
+			self.limits = limits.to_vec();
+		}
+
+		let mut tripped = Vec::new();
+
+		for (index, limit) in limits.iter().enumerate() {
+			let Some(value) = value(&limit.text_id) else {
+				continue;
+			};
+
+			let violated = limit.low.is_some_and(|low| value < low)
+				|| limit.high.is_some_and(|high| value > high);
+
+			if !violated {
+				self.excursions.remove(&index);
+				continue;
+			}
+
+			let excursion = self.excursions
+				.entry(index)
+				.or_insert(Excursion { since: now, tripped: false });
+
+			if excursion.tripped || now - excursion.since < limit.persistence {
+				continue;
+			}
+
+			excursion.tripped = true;
+			tripped.push(index);
+		}
+
+		tripped
+	}
+}
+
+/// Spawns a thread which runs the contingency, unless it is already running.
+fn run_contingency(shared: &SharedState, sequence: Sequence) {
+	warn!("Running contingency sequence '{}'.", sequence.name);
+
+	if let Err(error) = lifecycle::spawn(shared, sequence, None, false) {
+		warn!("Failed to run contingency sequence: {error}.");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	fn limit(low: Option<f64>, high: Option<f64>) -> Limit {
+		Limit {
+			text_id: "pt".to_owned(),
+			severity: LimitSeverity::Caution,
+			low,
+			high,
+			persistence: Duration::from_millis(100),
+			action: LimitAction::Alarm,
+		}
+	}
+
+	#[test]
+	fn low_and_high_limits_on_one_reading_persist_separately() {
+		let limits = vec![limit(Some(10.0), None), limit(None, Some(100.0))];
+		let mut monitor = LimitMonitor::default();
+		let start = Instant::now();
+
+		assert!(monitor.evaluate(&limits, start, |_| Some(5.0)).is_empty());
+		assert!(monitor.evaluate(&limits, start + Duration::from_millis(50), |_| Some(5.0)).is_empty());
+		assert_eq!(monitor.evaluate(&limits, start + Duration::from_millis(150), |_| Some(5.0)), vec![0]);
+
+		// a limit trips only once per excursion
+		assert!(monitor.evaluate(&limits, start + Duration::from_millis(200), |_| Some(5.0)).is_empty());
+
+		assert!(monitor.evaluate(&limits, start + Duration::from_millis(250), |_| Some(150.0)).is_empty());
+		assert_eq!(monitor.evaluate(&limits, start + Duration::from_millis(400), |_| Some(150.0)), vec![1]);
+	}
+
+	#[test]
+	fn stale_readings_neither_trip_nor_clear() {
+		let limits = vec![limit(Some(10.0), None)];
+		let mut monitor = LimitMonitor::default();
+		let start = Instant::now();
+
+		assert!(monitor.evaluate(&limits, start, |_| Some(5.0)).is_empty());
+		assert!(monitor.evaluate(&limits, start + Duration::from_millis(150), |_| None).is_empty());
+		assert_eq!(monitor.evaluate(&limits, start + Duration::from_millis(200), |_| Some(5.0)), vec![0]);
+	}
+
+	#[test]
+	fn replacing_limits_forgets_excursions() {
+		let mut monitor = LimitMonitor::default();
+		let start = Instant::now();
+
+		assert!(monitor.evaluate(&[limit(Some(10.0), None)], start, |_| Some(5.0)).is_empty());
+
+		let replaced = [limit(Some(20.0), None)];
+		assert!(monitor.evaluate(&replaced, start + Duration::from_millis(150), |_| Some(5.0)).is_empty());
+		assert_eq!(monitor.evaluate(&replaced, start + Duration::from_millis(250), |_| Some(5.0)), vec![0]);
+	}
+}
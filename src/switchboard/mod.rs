@@ -1,15 +1,19 @@
+#[allow(clippy::module_inception)]
 mod switchboard;
 mod worker;
 mod lifetime;
 mod defibrillator;
 mod commander;
+mod valve_monitor;
+mod limit_monitor;
 
 use switchboard::switchboard;
 use lifetime::lifetime;
 use worker::worker;
 use defibrillator::defibrillator;
 use commander::commander;
-use std::{collections::{HashMap, HashSet}, io, net::UdpSocket, sync::{mpsc, Arc, Mutex, RwLock}, thread};
+pub use worker::Worker;
+use std::{collections::HashMap, io, net::UdpSocket, sync::{mpsc, Arc, RwLock}};
 use crate::{state::SharedState, CommandSender};
 
 // Concerns: might be a bit too abort happy?
@@ -24,14 +28,14 @@ pub fn start(shared: SharedState, socket: UdpSocket) -> io::Result<CommandSender
   let (gig_tx, gig_rx) = mpsc::channel();
   let (command_tx, command_rx) = mpsc::channel();
 
-  let statuses = Arc::new(Mutex::new(HashSet::new()));
+  let statuses = shared.connected_boards.clone();
   let sockets = Arc::new(RwLock::new(HashMap::new()));
   
-  thread::spawn(switchboard(shared.clone(), snooze_tx, gig_tx, socket, reciever, sockets.clone()));
-  thread::spawn(lifetime(shared.clone(), snooze_rx, statuses.clone()));
-  thread::spawn(defibrillator(shared.clone(), sender, sockets.clone(), statuses.clone()));
-  thread::spawn(worker(shared.clone(), gig_rx));
-  thread::spawn(commander(shared.clone(), command_rx, command_sender, sockets.clone()));
+  shared.spawn("switchboard", switchboard(shared.clone(), snooze_tx, gig_tx, socket, reciever, sockets.clone()));
+  shared.spawn("lifetime", lifetime(shared.clone(), snooze_rx, statuses.clone()));
+  shared.spawn("defibrillator", defibrillator(shared.clone(), sender, sockets.clone(), statuses.clone()));
+  shared.spawn("worker", worker(shared.clone(), gig_rx));
+  shared.spawn("commander", commander(shared.clone(), command_rx, command_sender, sockets.clone()));
 
   Ok(command_tx)
 }
\ No newline at end of file
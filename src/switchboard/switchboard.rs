@@ -1,10 +1,10 @@
 use std::{collections::HashMap, net::{SocketAddr, UdpSocket}, sync::{mpsc::Sender, Arc, RwLock}};
 use common::comm::{BoardId, DataMessage, DataPoint};
 use jeflog::{fail, pass, warn};
-use crate::{handler, state::SharedState, FC_BOARD_ID};
+use crate::{events::{self, EventKind, Severity}, handler, recorder, state::SharedState, FC_BOARD_ID};
 
 /// Wakes when there's something to be passed along. Think of it like a telephone operator.
-pub fn switchboard(shared: SharedState, snooze: Sender<BoardId>, gig: Sender<(BoardId, Vec<DataPoint>)>, handshake_sender: UdpSocket, reciever: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>) -> impl FnOnce() -> () {
+pub fn switchboard(shared: SharedState, snooze: Sender<BoardId>, gig: Sender<(BoardId, Vec<DataPoint>)>, handshake_sender: UdpSocket, reciever: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>) -> impl FnOnce() {
   move || {
     let mut buffer = [0; crate::DATA_MESSAGE_BUFFER_SIZE];
 
@@ -19,6 +19,11 @@ pub fn switchboard(shared: SharedState, snooze: Sender<BoardId>, gig: Sender<(Bo
         }
       };
 
+      recorder::record(&shared, recorder::Entry::DataMessage {
+        sender: sender_address,
+        message: buffer[..message_length].to_vec(),
+      });
+
       // Interpret the data in the buffer
       let incoming_data = match postcard::from_bytes::<DataMessage>(&buffer[..message_length]) {
         Ok(data) => data,
@@ -34,6 +39,7 @@ pub fn switchboard(shared: SharedState, snooze: Sender<BoardId>, gig: Sender<(Bo
           sockets.insert(board_id.clone(), sender_address);
 
           pass!("Recieved identity message from board {board_id}");
+          events::emit(&shared, Severity::Info, module_path!(), EventKind::BoardConnected(board_id.clone()));
 					
 					let identity = DataMessage::Identity(String::from(FC_BOARD_ID));
 
@@ -0,0 +1,83 @@
+use std::{collections::HashMap, time::Instant};
+use common::comm::ValveState;
+use jeflog::fail;
+use crate::{events::{self, EventKind, Severity}, state::SharedState, DEFAULT_VALVE_SETTLE_TIME};
+
+/// A valve whose actual state currently disagrees with its commanded state.
+struct Mismatch {
+	/// The commanded state at the time the mismatch began.
+	commanded: ValveState,
+
+	/// When the mismatch was first observed.
+	since: Instant,
+
+	/// Whether an alarm has already been raised for this mismatch.
+	alarmed: bool,
+}
+
+/// Watches for valves whose actual state disagrees with their commanded state for
+/// longer than their settle time.
+#[derive(Default)]
+pub struct ValveMonitor {
+	mismatches: HashMap<String, Mismatch>,
+}
+
+impl ValveMonitor {
+	/// Checks every valve for mismatches, raising an alarm once for each mismatch which has
+	/// outlasted its settle time. Returns `true` if a raised alarm requires an abort.
+	pub fn check(&mut self, shared: &SharedState) -> bool {
+		let vehicle_state = shared.vehicle_state.lock().unwrap();
+		let mapping_options = shared.mapping_options.lock().unwrap();
+		let now = shared.clock.now();
+		let mut abort = false;
+
+		for (name, state) in &vehicle_state.valve_states {
+			// a valve which was never commanded has nothing to disagree with
+			let mismatched = matches!(state.commanded, ValveState::Open | ValveState::Closed)
+				&& state.actual != state.commanded;
+
+			if !mismatched {
+				self.mismatches.remove(name);
+				continue;
+			}
+
+			// restart the settle time whenever the valve is commanded to a new state
+			let restart = self.mismatches
+				.get(name)
+				.map_or(true, |mismatch| mismatch.commanded != state.commanded);
+
+			if restart {
+				self.mismatches.insert(name.clone(), Mismatch {
+					commanded: state.commanded,
+					since: now,
+					alarmed: false,
+				});
+			}
+
+			let mismatch = self.mismatches.get_mut(name).unwrap();
+			let options = mapping_options.get(name);
+
+			let settle_time = options
+				.and_then(|options| options.valve_settle_time)
+				.unwrap_or(DEFAULT_VALVE_SETTLE_TIME);
+
+			if mismatch.alarmed || now - mismatch.since < settle_time {
+				continue;
+			}
+
+			mismatch.alarmed = true;
+			fail!("Valve '{name}' was commanded {} but has been {} for longer than {settle_time:?}.", state.commanded, state.actual);
+
+			let aborting = options.is_some_and(|options| options.abort_on_valve_mismatch);
+			abort |= aborting;
+
+			events::emit(shared, if aborting { Severity::Critical } else { Severity::Warning }, module_path!(), EventKind::ValveMismatch {
+				name: name.clone(),
+				commanded: state.commanded,
+				actual: state.actual,
+			});
+		}
+
+		abort
+	}
+}
@@ -1,13 +1,16 @@
-use std::sync::{mpsc::Receiver, Arc, Mutex};
-use common::comm::{BoardId, ChannelType, CompositeValveState, DataPoint, Measurement, NodeMapping, SensorType, Unit, ValveState, VehicleState};
+use std::{sync::mpsc::Receiver, thread};
+use common::comm::{BoardId, ChannelType, CompositeValveState, DataPoint, Measurement, SensorType, Unit, ValveState};
 use jeflog::{fail, warn};
-use crate::{handler, state::SharedState};
+use crate::{handler, history::SensorHistory, inventory::RawDataPoint, operator::ValveThresholds, recorder, staleness::ReadingTimestamp, state::SharedState, DEFAULT_STALE_AFTER};
+use super::{limit_monitor::LimitMonitor, valve_monitor::ValveMonitor};
 
 /// deals with all the data processing, only wakes when there's data to be processed.
-pub fn worker(shared: SharedState, gig: Receiver<(BoardId, Vec<DataPoint>)>) -> impl FnOnce() -> () {
+pub fn worker(shared: SharedState, gig: Receiver<(BoardId, Vec<DataPoint>)>) -> impl FnOnce() {
   move || {
+    let mut worker = Worker::default();
+
     for (board_id, datapoints) in gig {
-      process_sam_data(shared.vehicle_state.clone(), shared.mappings.clone(), board_id, datapoints)
+      worker.process(&shared, board_id, datapoints);
     }
 
     fail!("Switchboard has unexpectedly closed the gig channel. Aborting and committing suicide...");
@@ -15,12 +18,83 @@ pub fn worker(shared: SharedState, gig: Receiver<(BoardId, Vec<DataPoint>)>) ->
   }
 }
 
-fn process_sam_data(vehicle_state: Arc<Mutex<VehicleState>>, mappings: Arc<Mutex<Vec<NodeMapping>>>, board_id: BoardId, datapoints: Vec<DataPoint>) {
-	let mut vehicle_state = vehicle_state.lock().unwrap();
+/// Processes batches of data points into the vehicle state and checks the monitors which depend on it.
+#[derive(Default)]
+pub struct Worker {
+	valve_monitor: ValveMonitor,
+	limit_monitor: LimitMonitor,
+}
+
+impl Worker {
+	/// Processes a single batch of data points from a board.
+	pub fn process(&mut self, shared: &SharedState, board_id: BoardId, datapoints: Vec<DataPoint>) {
+		process_sam_data(shared, board_id, datapoints);
+
+		// both monitors must be checked every time, so don't short-circuit
+		let valve_abort = self.valve_monitor.check(shared);
+		let limit_abort = self.limit_monitor.check(shared);
+
+		if !(valve_abort || limit_abort) {
+			return;
+		}
+
+		// the abort sequence re-commands valves, which may raise alarms of its own, so only
+		// the first alarm aborts until the operator re-arms the vehicle
+		let aborting = shared.sequences.lock().unwrap().contains_left("abort")
+			|| *shared.abort_latched.lock().unwrap();
+
+		if aborting {
+			warn!("Alarm requires an abort, but the vehicle is already aborted.");
+			return;
+		}
+
+		fail!("Alarm requires an abort. Aborting...");
+
+		// abort on a separate thread so that data continues to be processed while the abort sequence runs
+		let shared = shared.clone();
+		thread::spawn(move || handler::abort(&shared));
+	}
+}
+
+fn process_sam_data(shared: &SharedState, board_id: BoardId, datapoints: Vec<DataPoint>) {
+	let mut vehicle_state = shared.vehicle_state.lock().unwrap();
+
+	let mappings = shared.mappings.lock().unwrap();
+	let mapping_options = shared.mapping_options.lock().unwrap();
+	let mut reading_timestamps = shared.reading_timestamps.lock().unwrap();
+	let mut sensor_history = shared.sensor_history.lock().unwrap();
+	let mut channel_inventory = shared.channel_inventory.lock().unwrap();
+	let raw_passthrough = *shared.raw_passthrough.lock().unwrap();
+	let received = shared.clock.now();
 
-	let mappings = mappings.lock().unwrap();
+	// everything changed by this batch, which is recorded once all locks are released
+	let mut changed_readings = Vec::new();
+	let mut changed_valves = Vec::new();
+	let mut raw_data = Vec::new();
 
 	for data_point in datapoints {
+		let mapped = mappings.iter().any(|mapping| {
+			data_point.channel == mapping.channel
+				&& mapping.sensor_type.channel_types().contains(&data_point.channel_type)
+				&& *board_id == mapping.board_id
+		});
+
+		channel_inventory.observe(&board_id, &data_point, mapped, received);
+
+		// unmapped data points are only kept in raw mode, to help bring up new boards
+		if !mapped {
+			if raw_passthrough {
+				raw_data.push(RawDataPoint {
+					board_id: board_id.clone(),
+					channel: data_point.channel,
+					channel_type: data_point.channel_type,
+					value: data_point.value,
+				});
+			}
+
+			continue;
+		}
+
 		for mapping in &*mappings {
 			// checks if this mapping corresponds to the data point and, if not, continues
 			// originally, I intended to implement this with a HashMap, but considering how
@@ -104,22 +178,58 @@ fn process_sam_data(vehicle_state: Arc<Mutex<VehicleState>>, mappings: Arc<Mutex
 						},
 					};
 
-					let actual_state = estimate_valve_state(voltage, current, mapping.powered_threshold, mapping.normally_closed);
+					let thresholds = mapping_options
+						.get(&mapping.text_id)
+						.and_then(|options| options.valve_thresholds)
+						.unwrap_or_default();
+
+					let previous_state = vehicle_state.valve_states
+						.get(&mapping.text_id)
+						.map_or(ValveState::Undetermined, |state| state.actual);
+
+					let actual_state = estimate_valve_state(voltage, current, mapping.powered_threshold, mapping.normally_closed, thresholds, previous_state);
 
 					if let Some(existing) = vehicle_state.valve_states.get_mut(&mapping.text_id) {
 						existing.actual = actual_state;
+						changed_valves.push((mapping.text_id.clone(), *existing));
 					} else {
-						vehicle_state.valve_states.insert(mapping.text_id.clone(), CompositeValveState {
+						let state = CompositeValveState {
 							commanded: ValveState::Undetermined,
 							actual: actual_state
-						});
+						};
+
+						changed_valves.push((mapping.text_id.clone(), state));
+						vehicle_state.valve_states.insert(mapping.text_id.clone(), state);
 					}
 
 					measurement
 				},
 			};
 
-			// replace item without cloning string if already present
+			let stale_after = mapping_options
+				.get(&mapping.text_id)
+				.and_then(|options| options.stale_after)
+				.unwrap_or(DEFAULT_STALE_AFTER);
+
+			let timestamp = ReadingTimestamp { received, stale_after };
+
+			// replace items without cloning string if already present
+			if let Some(existing) = reading_timestamps.get_mut(&text_id) {
+				*existing = timestamp;
+			} else {
+				reading_timestamps.insert(text_id.clone(), timestamp);
+			}
+
+			if let Some(history) = sensor_history.get_mut(&text_id) {
+				history.push(received, measurement.value);
+			} else {
+				let mut history = SensorHistory::new();
+				history.push(received, measurement.value);
+				sensor_history.insert(text_id.clone(), history);
+			}
+
+			changed_readings.push((text_id.clone(), measurement));
+
 			if let Some(existing) = vehicle_state.sensor_readings.get_mut(&text_id) {
 				*existing = measurement;
 			} else {
@@ -127,21 +237,63 @@ fn process_sam_data(vehicle_state: Arc<Mutex<VehicleState>>, mappings: Arc<Mutex
 			}
 		}
 	}
+
+	drop(vehicle_state);
+	drop(mappings);
+	drop(mapping_options);
+	drop(reading_timestamps);
+	drop(sensor_history);
+	drop(channel_inventory);
+
+	shared.vehicle_updated.notify_all();
+
+	recorder::record(shared, recorder::Entry::StateUpdate {
+		sensor_readings: changed_readings,
+		valve_states: changed_valves,
+	});
+
+	if !raw_data.is_empty() {
+		recorder::record(shared, recorder::Entry::RawData(raw_data));
+	}
 }
 
-/// Estimates the state of a valve given its voltage, current, and the current threshold at which it is considered powered.
-fn estimate_valve_state(voltage: f64, current: f64, powered_threshold: Option<f64>, normally_closed: Option<bool>) -> ValveState {
-	// calculate the actual state of the valve, assuming that it's normally closed
-	let mut estimated = match powered_threshold {
+/// Estimates the state of a valve given its voltage, current, the current threshold at which it is considered powered,
+/// and its previous estimated state, which is used to apply hysteresis to each threshold.
+fn estimate_valve_state(voltage: f64, current: f64, powered_threshold: Option<f64>, normally_closed: Option<bool>, thresholds: ValveThresholds, previous: ValveState) -> ValveState {
+	// the previous state as it would have been estimated for a normally closed valve
+	let previous = normalize_valve_state(previous, normally_closed);
+
+	// calculate the actual state of the valve, assuming that it's normally closed.
+	// each threshold is shifted away from the previous estimate so that a reading
+	// must cross it by the hysteresis margin before the estimate changes.
+	let estimated = match powered_threshold {
 		Some(powered) => {
+			let powered = match previous {
+				ValveState::Open | ValveState::Fault => powered - thresholds.current_hysteresis,
+				ValveState::Closed | ValveState::Disconnected => powered + thresholds.current_hysteresis,
+				ValveState::Undetermined => powered,
+			};
+
 			if current < powered { // valve is unpowered
-				if voltage < 4.0 {
+				let closed_voltage = match previous {
+					ValveState::Closed => thresholds.closed_voltage + thresholds.voltage_hysteresis,
+					ValveState::Disconnected => thresholds.closed_voltage - thresholds.voltage_hysteresis,
+					_ => thresholds.closed_voltage,
+				};
+
+				if voltage < closed_voltage {
 					ValveState::Closed
 				} else {
 					ValveState::Disconnected
 				}
 			 } else { // valve is powered
-				if voltage < 20.0 {
+				let open_voltage = match previous {
+					ValveState::Open => thresholds.open_voltage - thresholds.voltage_hysteresis,
+					ValveState::Fault => thresholds.open_voltage + thresholds.voltage_hysteresis,
+					_ => thresholds.open_voltage,
+				};
+
+				if voltage < open_voltage {
 					ValveState::Fault
 				} else {
 					ValveState::Open
@@ -151,13 +303,19 @@ fn estimate_valve_state(voltage: f64, current: f64, powered_threshold: Option<f6
 		None => ValveState::Fault,
 	};
 
-	if normally_closed == Some(false) {
-		estimated = match estimated {
-			ValveState::Open => ValveState::Closed,
-			ValveState::Closed => ValveState::Open,
-			other => other,
-		};
+	normalize_valve_state(estimated, normally_closed)
+}
+
+/// Swaps open and closed for normally open valves, converting between the estimated
+/// state of a normally closed valve and the actual state of the given valve.
+fn normalize_valve_state(state: ValveState, normally_closed: Option<bool>) -> ValveState {
+	if normally_closed != Some(false) {
+		return state;
 	}
 
-	estimated
-}
\ No newline at end of file
+	match state {
+		ValveState::Open => ValveState::Closed,
+		ValveState::Closed => ValveState::Open,
+		other => other,
+	}
+}
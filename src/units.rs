@@ -0,0 +1,245 @@
+use common::comm::Unit;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// The physical dimension of a unit. Values may only be converted between units of the
+/// same dimension.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Dimension {
+	Pressure,
+	Temperature,
+	Force,
+	Voltage,
+	Current,
+}
+
+/// A system of units which forwarded telemetry may be expressed in.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum UnitSystem {
+	Si,
+	Imperial,
+}
+
+/// A unit which sensor readings may be converted to and from.
+///
+/// This is a superset of `common::comm::Unit`, which only holds the units the worker
+/// produces readings in.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum UnitOfMeasure {
+	Psi,
+	Pascals,
+	Kilopascals,
+	Bar,
+	Kelvin,
+	Celsius,
+	Fahrenheit,
+	Pounds,
+	Newtons,
+	Kilonewtons,
+	Volts,
+	Millivolts,
+	Amps,
+	Milliamps,
+}
+
+/// Error returned when converting between units of different dimensions.
+#[derive(Clone, Copy, Debug)]
+pub struct IncompatibleUnits {
+	pub from: UnitOfMeasure,
+	pub to: UnitOfMeasure,
+}
+
+impl fmt::Display for IncompatibleUnits {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "cannot convert {} ({:?}) to {} ({:?})", self.from, self.from.dimension(), self.to, self.to.dimension())
+	}
+}
+
+impl UnitOfMeasure {
+	/// The physical dimension measured by this unit.
+	pub fn dimension(self) -> Dimension {
+		match self {
+			Self::Psi | Self::Pascals | Self::Kilopascals | Self::Bar => Dimension::Pressure,
+			Self::Kelvin | Self::Celsius | Self::Fahrenheit => Dimension::Temperature,
+			Self::Pounds | Self::Newtons | Self::Kilonewtons => Dimension::Force,
+			Self::Volts | Self::Millivolts => Dimension::Voltage,
+			Self::Amps | Self::Milliamps => Dimension::Current,
+		}
+	}
+
+	/// Converts a value in this unit to the SI base unit of its dimension.
+	fn to_base(self, value: f64) -> f64 {
+		match self {
+			Self::Psi => value * 6_894.757,
+			Self::Pascals => value,
+			Self::Kilopascals => value * 1_000.0,
+			Self::Bar => value * 100_000.0,
+			Self::Kelvin => value,
+			Self::Celsius => value + 273.15,
+			Self::Fahrenheit => (value - 32.0) * 5.0 / 9.0 + 273.15,
+			Self::Pounds => value * 4.448_222,
+			Self::Newtons => value,
+			Self::Kilonewtons => value * 1_000.0,
+			Self::Volts => value,
+			Self::Millivolts => value / 1_000.0,
+			Self::Amps => value,
+			Self::Milliamps => value / 1_000.0,
+		}
+	}
+
+	/// Converts a value in the SI base unit of this unit's dimension to this unit.
+	fn base_to_unit(self, value: f64) -> f64 {
+		match self {
+			Self::Psi => value / 6_894.757,
+			Self::Pascals => value,
+			Self::Kilopascals => value / 1_000.0,
+			Self::Bar => value / 100_000.0,
+			Self::Kelvin => value,
+			Self::Celsius => value - 273.15,
+			Self::Fahrenheit => (value - 273.15) * 9.0 / 5.0 + 32.0,
+			Self::Pounds => value / 4.448_222,
+			Self::Newtons => value,
+			Self::Kilonewtons => value / 1_000.0,
+			Self::Volts => value,
+			Self::Millivolts => value * 1_000.0,
+			Self::Amps => value,
+			Self::Milliamps => value * 1_000.0,
+		}
+	}
+
+	/// Converts a value in this unit to the target unit, failing if they measure different dimensions.
+	pub fn convert(self, value: f64, target: UnitOfMeasure) -> Result<f64, IncompatibleUnits> {
+		if self.dimension() != target.dimension() {
+			return Err(IncompatibleUnits { from: self, to: target });
+		}
+
+		Ok(target.base_to_unit(self.to_base(value)))
+	}
+
+	/// The unit used for this unit's dimension in the given system.
+	pub fn in_system(self, system: UnitSystem) -> UnitOfMeasure {
+		match (self.dimension(), system) {
+			(Dimension::Pressure, UnitSystem::Si) => Self::Kilopascals,
+			(Dimension::Pressure, UnitSystem::Imperial) => Self::Psi,
+			(Dimension::Temperature, UnitSystem::Si) => Self::Celsius,
+			(Dimension::Temperature, UnitSystem::Imperial) => Self::Fahrenheit,
+			(Dimension::Force, UnitSystem::Si) => Self::Newtons,
+			(Dimension::Force, UnitSystem::Imperial) => Self::Pounds,
+			(Dimension::Voltage, _) => Self::Volts,
+			(Dimension::Current, _) => Self::Amps,
+		}
+	}
+}
+
+impl From<Unit> for UnitOfMeasure {
+	fn from(unit: Unit) -> Self {
+		match unit {
+			Unit::Amps => Self::Amps,
+			Unit::Psi => Self::Psi,
+			Unit::Kelvin => Self::Kelvin,
+			Unit::Pounds => Self::Pounds,
+			Unit::Volts => Self::Volts,
+		}
+	}
+}
+
+impl fmt::Display for UnitOfMeasure {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let symbol = match self {
+			Self::Psi => "psi",
+			Self::Pascals => "Pa",
+			Self::Kilopascals => "kPa",
+			Self::Bar => "bar",
+			Self::Kelvin => "K",
+			Self::Celsius => "°C",
+			Self::Fahrenheit => "°F",
+			Self::Pounds => "lbf",
+			Self::Newtons => "N",
+			Self::Kilonewtons => "kN",
+			Self::Volts => "V",
+			Self::Millivolts => "mV",
+			Self::Amps => "A",
+			Self::Milliamps => "mA",
+		};
+
+		write!(f, "{symbol}")
+	}
+}
+
+impl FromStr for UnitOfMeasure {
+	type Err = String;
+
+	fn from_str(symbol: &str) -> Result<Self, Self::Err> {
+		let unit = match symbol {
+			"psi" => Self::Psi,
+			"Pa" => Self::Pascals,
+			"kPa" => Self::Kilopascals,
+			"bar" => Self::Bar,
+			"K" => Self::Kelvin,
+			"C" | "°C" | "degC" => Self::Celsius,
+			"F" | "°F" | "degF" => Self::Fahrenheit,
+			"lbf" | "lb" => Self::Pounds,
+			"N" => Self::Newtons,
+			"kN" => Self::Kilonewtons,
+			"V" => Self::Volts,
+			"mV" => Self::Millivolts,
+			"A" => Self::Amps,
+			"mA" => Self::Milliamps,
+			other => return Err(format!("unknown unit '{other}'")),
+		};
+
+		Ok(unit)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn assert_close(actual: f64, expected: f64) {
+		assert!((actual - expected).abs() < 1e-6 * expected.abs().max(1.0), "{actual} != {expected}");
+	}
+
+	#[test]
+	fn temperatures_convert_with_offsets() {
+		assert_close(UnitOfMeasure::Celsius.convert(100.0, UnitOfMeasure::Fahrenheit).unwrap(), 212.0);
+		assert_close(UnitOfMeasure::Fahrenheit.convert(-40.0, UnitOfMeasure::Celsius).unwrap(), -40.0);
+		assert_close(UnitOfMeasure::Kelvin.convert(0.0, UnitOfMeasure::Celsius).unwrap(), -273.15);
+	}
+
+	#[test]
+	fn scaled_units_convert_through_the_base_unit() {
+		assert_close(UnitOfMeasure::Psi.convert(1.0, UnitOfMeasure::Kilopascals).unwrap(), 6.894_757);
+		assert_close(UnitOfMeasure::Bar.convert(1.0, UnitOfMeasure::Kilopascals).unwrap(), 100.0);
+		assert_close(UnitOfMeasure::Pounds.convert(1.0, UnitOfMeasure::Newtons).unwrap(), 4.448_222);
+		assert_close(UnitOfMeasure::Millivolts.convert(2_500.0, UnitOfMeasure::Volts).unwrap(), 2.5);
+		assert_close(UnitOfMeasure::Amps.convert(0.02, UnitOfMeasure::Milliamps).unwrap(), 20.0);
+	}
+
+	#[test]
+	fn conversions_round_trip() {
+		for (from, to) in [
+			(UnitOfMeasure::Psi, UnitOfMeasure::Bar),
+			(UnitOfMeasure::Fahrenheit, UnitOfMeasure::Kelvin),
+			(UnitOfMeasure::Kilonewtons, UnitOfMeasure::Pounds),
+		] {
+			let converted = from.convert(123.4, to).unwrap();
+			assert_close(to.convert(converted, from).unwrap(), 123.4);
+		}
+	}
+
+	#[test]
+	fn different_dimensions_are_incompatible() {
+		let error = UnitOfMeasure::Psi.convert(1.0, UnitOfMeasure::Kelvin).unwrap_err();
+		assert_eq!((error.from, error.to), (UnitOfMeasure::Psi, UnitOfMeasure::Kelvin));
+	}
+
+	#[test]
+	fn symbols_parse_back_to_their_unit() {
+		for unit in [UnitOfMeasure::Kilopascals, UnitOfMeasure::Celsius, UnitOfMeasure::Milliamps] {
+			assert_eq!(unit.to_string().parse::<UnitOfMeasure>(), Ok(unit));
+		}
+
+		assert!("furlongs".parse::<UnitOfMeasure>().is_err());
+	}
+}
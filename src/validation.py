@@ -0,0 +1,128 @@
+# Statically checks sequence scripts against the current mappings before they are run.
+# Loaded by validation.rs, which calls validate() with the names of every mapped sensor
+# reading and valve, and whether any mappings have been received yet.
+
+import ast
+import builtins
+
+# methods of a valve which actuate it
+ACTUATIONS = {"open", "close"}
+
+# builtins whose first argument is the name of a sensor reading
+SENSOR_FUNCTIONS = {
+	"read_sensor_as",
+	"sensor_min",
+	"sensor_max",
+	"sensor_mean",
+	"sensor_stddev",
+	"sensor_rate",
+	"sensor_value_at",
+	"calibrate",
+	"wait_until_sensor",
+}
+
+# builtins whose first argument is the name of a valve
+VALVE_FUNCTIONS = {"wait_until_valve", "release_valve"}
+
+
+def validate(script, sensors, valves, mapped):
+	"""Returns a (severity, line, message) tuple for every problem found in a script.
+
+	Until mappings are received every device is unknown, so unknown devices are only warned
+	about rather than rejecting every script."""
+
+	try:
+		tree = ast.parse(script)
+	except SyntaxError as error:
+		return [("error", error.lineno, f"syntax error: {error.msg}")]
+
+	defined, star_import = defined_names(tree)
+	# library sequences are given their arguments in args
+	known = defined | set(dir(builtins)) | {"args"}
+	devices = sensors | valves
+	issues = []
+	reported = set()
+
+	for node in ast.walk(tree):
+		if isinstance(node, ast.Call):
+			issues.extend(check_call(node, sensors, valves, defined, known, reported, mapped))
+		elif isinstance(node, ast.Name) and isinstance(node.ctx, ast.Load):
+			# names can't be known once a module's contents are imported wholesale
+			if star_import or node.id in known or node.id in devices or node.id in reported:
+				continue
+
+			reported.add(node.id)
+			issues.append(("warning", node.lineno, f"unknown name '{node.id}'"))
+
+	return sorted(issues, key=lambda issue: issue[1] or 0)
+
+
+def check_call(call, sensors, valves, defined, known, reported, mapped):
+	"""Checks that a call refers to devices which exist and are of the right kind, adding
+	any unknown valve it actuates to the names already reported."""
+
+	function = call.func
+	unknown = "error" if mapped else "warning"
+
+	if isinstance(function, ast.Attribute) and function.attr in ACTUATIONS and isinstance(function.value, ast.Name):
+		name = function.value.id
+
+		if name in valves or name in known:
+			return []
+
+		if name in sensors:
+			return [("error", call.lineno, f"sensor '{name}' cannot be actuated")]
+
+		reported.add(name)
+		return [(unknown, call.lineno, f"unknown valve '{name}'")]
+
+	# only builtins which the script hasn't shadowed are checked
+	if not isinstance(function, ast.Name) or function.id in defined or not call.args:
+		return []
+
+	argument = call.args[0]
+
+	if not isinstance(argument, ast.Constant) or not isinstance(argument.value, str):
+		return []
+
+	name = argument.value
+
+	if function.id in SENSOR_FUNCTIONS and name not in sensors:
+		return [(unknown, call.lineno, f"unknown sensor '{name}' passed to {function.id}")]
+
+	if function.id in VALVE_FUNCTIONS and name not in valves:
+		if name in sensors:
+			return [("error", call.lineno, f"sensor '{name}' passed to {function.id}, which expects a valve")]
+
+		return [(unknown, call.lineno, f"unknown valve '{name}' passed to {function.id}")]
+
+	return []
+
+
+def defined_names(tree):
+	"""Every name the script defines or imports, and whether it imports any module wholesale."""
+
+	defined = set()
+	star_import = False
+
+	for node in ast.walk(tree):
+		if isinstance(node, ast.Name) and not isinstance(node.ctx, ast.Load):
+			defined.add(node.id)
+		elif isinstance(node, (ast.FunctionDef, ast.AsyncFunctionDef, ast.ClassDef)):
+			defined.add(node.name)
+		elif isinstance(node, ast.arg):
+			defined.add(node.arg)
+		elif isinstance(node, (ast.Import, ast.ImportFrom)):
+			for alias in node.names:
+				if alias.name == "*":
+					star_import = True
+				else:
+					defined.add(alias.asname or alias.name.split(".")[0])
+		elif isinstance(node, ast.ExceptHandler) and node.name:
+			defined.add(node.name)
+		elif isinstance(node, (ast.Global, ast.Nonlocal)):
+			defined.update(node.names)
+		elif isinstance(node, (ast.MatchAs, ast.MatchStar)) and node.name:
+			defined.add(node.name)
+
+	return defined, star_import
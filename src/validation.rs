@@ -0,0 +1,107 @@
+use crate::{operator::{self, FlightReport}, state::SharedState};
+use common::comm::{Sequence, SensorType};
+use jeflog::{fail, warn};
+use pyo3::{types::PyModule, PyResult, Python};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// The Python source of the validator, which uses Python's own parser.
+const VALIDATOR: &str = include_str!("validation.py");
+
+/// Whether a problem found in a sequence prevents it from running.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum IssueSeverity {
+	/// Likely a mistake, but the sequence may still run.
+	Warning,
+
+	/// The sequence is rejected.
+	Error,
+}
+
+/// A problem found in a sequence before running it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ValidationIssue {
+	pub severity: IssueSeverity,
+
+	/// The line of the script on which the problem is, if known.
+	pub line: Option<u32>,
+
+	pub message: String,
+}
+
+/// Statically checks a sequence script against the current mappings for syntax errors,
+/// unknown device names and actuation of sensors.
+pub fn validate(shared: &SharedState, script: &str) -> Vec<ValidationIssue> {
+	let mut sensors = HashSet::new();
+	let mut valves = HashSet::new();
+	let mappings = shared.mappings.lock().unwrap();
+	let mapped = !mappings.is_empty();
+
+	for mapping in mappings.iter() {
+		if matches!(mapping.sensor_type, SensorType::Valve) {
+			// the worker stores a valve's voltage and current as sensor readings
+			sensors.insert(format!("{}_V", mapping.text_id));
+			sensors.insert(format!("{}_I", mapping.text_id));
+			valves.insert(mapping.text_id.clone());
+		} else {
+			sensors.insert(mapping.text_id.clone());
+		}
+	}
+
+	drop(mappings);
+
+	let result = Python::with_gil(|py| -> PyResult<Vec<(String, Option<u32>, String)>> {
+		PyModule::from_code(py, VALIDATOR, "validation.py", "validation")?
+			.getattr("validate")?
+			.call1((script, sensors, valves, mapped))?
+			.extract()
+	});
+
+	match result {
+		Ok(issues) => issues
+			.into_iter()
+			.map(|(severity, line, message)| ValidationIssue {
+				severity: if severity == "error" { IssueSeverity::Error } else { IssueSeverity::Warning },
+				line,
+				message,
+			})
+			.collect(),
+		// a broken validator shouldn't prevent sequences from running
+		Err(error) => vec![ValidationIssue {
+			severity: IssueSeverity::Warning,
+			line: None,
+			message: format!("failed to validate sequence: {error}"),
+		}],
+	}
+}
+
+/// Validates a sequence before it is run or stored, logging and reporting any issues to
+/// the operator. Returns whether the sequence is free of errors.
+pub fn check(shared: &SharedState, sequence: &Sequence) -> bool {
+	let issues = validate(shared, &sequence.script);
+
+	if issues.is_empty() {
+		return true;
+	}
+
+	let mut valid = true;
+
+	for issue in &issues {
+		let line = issue.line.map_or(String::new(), |line| format!(" on line {line}"));
+
+		match issue.severity {
+			IssueSeverity::Warning => warn!("Sequence '{}'{line}: {}.", sequence.name, issue.message),
+			IssueSeverity::Error => {
+				fail!("Sequence '{}'{line}: {}.", sequence.name, issue.message);
+				valid = false;
+			},
+		}
+	}
+
+	operator::report(shared, &FlightReport::Validation {
+		name: sequence.name.clone(),
+		issues,
+	});
+
+	valid
+}